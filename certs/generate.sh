@@ -0,0 +1,22 @@
+#!/bin/sh
+# Generates a self-signed CA, a certificate for the router and a client certificate for internal callers
+# Usage: ./certs/generate.sh [output directory]
+set -e
+DIR=${1:-certs}
+cd "$DIR"
+
+openssl req -x509 -newkey rsa:2048 -nodes -days 365 -subj "/CN=router-ca" \
+    -keyout ca-key.pem -out ca.pem
+
+openssl req -newkey rsa:2048 -nodes -subj "/CN=localhost" \
+    -keyout router-key.pem -out router.csr
+printf "subjectAltName=DNS:localhost,IP:127.0.0.1" > router.ext
+openssl x509 -req -in router.csr -CA ca.pem -CAkey ca-key.pem -CAcreateserial \
+    -days 365 -extfile router.ext -out router.pem
+
+openssl req -newkey rsa:2048 -nodes -subj "/CN=internal-client" \
+    -keyout client-key.pem -out client.csr
+openssl x509 -req -in client.csr -CA ca.pem -CAkey ca-key.pem -CAcreateserial \
+    -days 365 -out client.pem
+
+rm -f router.csr router.ext client.csr ca.srl
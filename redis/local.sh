@@ -0,0 +1,54 @@
+#!/bin/sh
+# Starts local redis-server processes to test the cache with Sentinel or Cluster
+# Usage: ./redis/local.sh sentinel|cluster [work directory]
+#   sentinel: a master on 6380, a replica on 6381 and three sentinels on 26379-26381 monitoring "mymaster"
+#             stop the master (redis-cli -p 6380 shutdown nosave) to watch the router follow the failover
+#   cluster:  three masters on 7000-7002 and three replicas on 7003-7005
+# Every process runs in the background, stop them with: pkill -f "redis-server 127.0.0.1"
+set -e
+MODE=$1
+DIR=${2:-/tmp/router-redis}
+mkdir -p "$DIR"
+cd "$DIR"
+
+case "$MODE" in
+sentinel)
+    redis-server --bind 127.0.0.1 --port 6380 --daemonize yes --dir "$DIR" --logfile 6380.log
+    redis-server --bind 127.0.0.1 --port 6381 --daemonize yes --dir "$DIR" --logfile 6381.log --replicaof 127.0.0.1 6380
+    for port in 26379 26380 26381; do
+        cat > sentinel-$port.conf <<CONF
+bind 127.0.0.1
+port $port
+sentinel monitor mymaster 127.0.0.1 6380 2
+sentinel down-after-milliseconds mymaster 2000
+sentinel failover-timeout mymaster 5000
+CONF
+        redis-server sentinel-$port.conf --sentinel --daemonize yes --logfile sentinel-$port.log
+    done
+    cat <<CONF
+Add to router.toml:
+[cache.sentinel]
+master = "mymaster"
+addresses = ["127.0.0.1:26379", "127.0.0.1:26380", "127.0.0.1:26381"]
+CONF
+    ;;
+cluster)
+    for port in 7000 7001 7002 7003 7004 7005; do
+        mkdir -p $port
+        redis-server --bind 127.0.0.1 --port $port --daemonize yes --dir "$DIR/$port" --logfile "$DIR/$port/redis.log" \
+            --cluster-enabled yes --cluster-config-file nodes.conf --cluster-node-timeout 2000
+    done
+    sleep 1
+    redis-cli --cluster create 127.0.0.1:7000 127.0.0.1:7001 127.0.0.1:7002 \
+        127.0.0.1:7003 127.0.0.1:7004 127.0.0.1:7005 --cluster-replicas 1 --cluster-yes
+    cat <<CONF
+Add to router.toml:
+[cache.cluster]
+seeds = ["127.0.0.1:7000", "127.0.0.1:7001", "127.0.0.1:7002"]
+CONF
+    ;;
+*)
+    echo "Usage: $0 sentinel|cluster [work directory]" >&2
+    exit 1
+    ;;
+esac
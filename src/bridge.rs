@@ -0,0 +1,70 @@
+//  Notification bridge
+//  A comment is posted to one instance of the router, but the readers of its topic can be connected to any other one
+//  The BridgeActor publishes the updates of this instance to a Redis channel, and the updates of other instances that
+//  arrive on the channel are relayed to the local RepeaterActor. Every update carries the id of the instance that
+//  published it, an instance drops its own updates when they come back and relayed updates are never published again
+use actix::{Actor, Addr, Context, Handler};
+use log::{debug, warn};
+use serde_derive::{Deserialize, Serialize};
+use crate::cache::{self, Publish, PublisherActor, RedisNodes};
+use crate::repeater::{RepeaterActor, RepeaterUpdate};
+use crate::NewComment;
+
+//  Updates are published as JSON
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    origin: String,
+    topic: String,
+    comment: NewComment,
+}
+
+pub struct BridgeActor {
+    publisher: Addr<PublisherActor>,
+    channel: String,
+    instance: String,
+}
+
+impl BridgeActor {
+    pub fn new(publisher: Addr<PublisherActor>, channel: &str, instance: &str) -> Self {
+        Self {
+            publisher,
+            channel: channel.to_owned(),
+            instance: instance.to_owned(),
+        }
+    }
+}
+
+impl Actor for BridgeActor {
+    type Context = Context<Self>;
+}
+
+impl Handler<RepeaterUpdate> for BridgeActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: RepeaterUpdate, _: &mut Self::Context) -> Self::Result {
+        let envelope = Envelope {
+            origin: self.instance.clone(),
+            topic: msg.topic,
+            comment: msg.comment,
+        };
+        match serde_json::to_string(&envelope) {
+            Ok(payload) => self.publisher.do_send(Publish { channel: self.channel.clone(), payload }),
+            Err(err) => warn!("Can't publish the comment on {}: {}", envelope.topic, err),
+        }
+    }
+}
+
+//  Relays the updates of other instances to the RepeaterActor
+pub fn relay(nodes: RedisNodes, channel: String, instance: String, repeater: Addr<RepeaterActor>) {
+    let name = channel.clone();
+    cache::subscribe(nodes, channel, move |payload| {
+        match serde_json::from_str::<Envelope>(&payload) {
+            Ok(ref envelope) if envelope.origin == instance => { }
+            Ok(Envelope { origin, topic, comment }) => {
+                debug!("Comment on {} received from {}", topic, origin);
+                repeater.do_send(RepeaterUpdate { seq: 0, epoch: String::new(), topic, comment, origin: Some(origin) });
+            }
+            Err(err) => warn!("Invalid message on {}: {}", name, err),
+        }
+    });
+}
@@ -1,48 +1,157 @@
 use actix::prelude::*;
-use failure::Error;
-use futures::Future;
-use redis::{Commands, Client, RedisError};
+use actix::fut;
+use failure::{format_err, Error};
+use futures::{future, Future};
+use log::warn;
+use std::collections::{BTreeMap, HashMap};
+use crate::config::{CacheConfig, CacheKind, ConfigUpdate};
 
-//  Our actor has to keep an instance of CLient. WE well be using multpile actors for handling parallel request to a database 
+mod backend;
+pub use self::backend::{redis_address, CacheBackend, RedisBackend, RedisNodes};
+mod cluster;
+mod sentinel;
+pub use self::sentinel::SentinelActor;
+use self::backend::Reply;
+mod breaker;
+pub use self::breaker::{Breaker, BreakerStats};
+mod memory;
+pub use self::memory::MemoryBackend;
+mod pubsub;
+pub use self::pubsub::{instance_id, subscribe, Publish, PublisherActor};
+mod invalidation;
+pub use self::invalidation::{subscribe_invalidations, InvalidationActor};
+mod local;
+pub use self::local::TieredCache;
+mod flight;
+pub use self::flight::SingleFlight;
+mod generation;
+pub use self::generation::Generations;
+mod codec;
+mod stream;
+pub use self::stream::RedisStream;
+mod entry;
+pub use self::entry::{Directives, Entry};
+mod stats;
+pub use self::stats::Outcome;
+use self::stats::{KeyInfo, PathStats};
+
+//  Our actor has to keep a backend that stores the values 
+//  The backend returns futures, so a single actor serves all workers without waiting for Redis between messages 
+//  Failures of the backend don't fail requests: reads become misses and the breaker stops using the backend for a while 
+//  The actor also counts hits and misses of every cached path for the admin API 
 pub struct CacheActor { 
-    client: Client,
-    expiration: usize,
-    //  TTL period
+    backend: Box<dyn CacheBackend>,
+    breaker: Breaker,
+    paths: HashMap<String, PathStats>,
 }
 
-//  Uses provided address string to create a Client instance, and adds both the client and expiration values to the CacheActor struct 
+//  Adds the backend to the CacheActor struct, the TTL period comes with every SetValue message 
 impl CacheActor { 
-    pub fn new(addr: &str, expiration: usize) -> Self { 
-        let client = Client::open(addr).unwrap();
-
+    pub fn new(backend: Box<dyn CacheBackend>, breaker: Breaker) -> Self { 
         Self { 
-            client, expiration
+            backend,
+            breaker,
+            paths: HashMap::new(),
         }
     }
+
+    //  Counts the result of a backend operation once it completes 
+    fn guard<T: 'static>(&mut self, reply: Reply<T>) -> ResponseActFuture<Self, T, Error> { 
+        let fut = fut::wrap_future::<_, Self>(reply)
+            .then(|res, act, _| { 
+                match &res { 
+                    Ok(_) => act.breaker.success(),
+                    Err(err) => { 
+                        warn!("Cache backend error: {}", err);
+                        act.breaker.failure();
+                    }
+                }
+                fut::result(res)
+            });
+        Box::new(fut)
+    }
+}
+
+//  Creates the backend selected in the configuration, Redis connections go to the shared list of nodes 
+pub fn backend(config: &CacheConfig, nodes: &RedisNodes) -> Box<dyn CacheBackend> { 
+    match config.backend { 
+        CacheKind::Redis => Box::new(redis_backend(config, nodes, config.connections)),
+        CacheKind::Memory => Box::new(MemoryBackend::new(config.max_bytes)),
+    }
+}
+
+//  Redis connections of the configured topology, other users of Redis open their own ones 
+pub fn redis_backend(config: &CacheConfig, nodes: &RedisNodes, connections: usize) -> RedisBackend { 
+    match config.cluster { 
+        Some(_) => RedisBackend::cluster(nodes.get()),
+        None => RedisBackend::new(nodes.clone(), connections),
+    }
 }
 // Actor 
 impl Actor for CacheActor { 
-    type Context = SyncContext<Self>;
+    type Context = Context<Self>;
 }
 //  Messages
 //  To interact with CacheActor, we have to add two types of messages: to set a value and to get a value 
 
-//  Which provides a pair of key and new value for caching 
+//  Which provides a pair of key and new value for caching, the number of seconds the value is kept and the tags of the key 
 struct SetValue { 
     pub path: String,
-    pub content: Vec<u8>
+    pub content: Vec<u8>,
+    pub expiration: usize,
+    pub tags: Vec<String>,
 }
 //  Setting a value message
 impl Message for SetValue { 
-    type Result = Result<(), RedisError>;
+    type Result = Result<(), Error>;
 }
 //  CacheACtor has support for receiving SetValue messages 
 impl Handler<SetValue> for CacheActor { 
-    type Result = Result<(), RedisError>;
+    type Result = ResponseActFuture<Self, (), Error>;
 
     fn handle(&mut self, msg: SetValue, _: &mut Self::Context) -> Self::Result { 
-        self.client.set_ex(msg.path, msg.content, self.expiration)
-        //  We used a Client intance stored in CacheActor to executre the SETEX command from Redis with the set_ex method call 
+        //  Values aren't stored while the backend is unavailable 
+        if !self.breaker.allow() { 
+            return Box::new(fut::ok(()));
+        }
+        //  The backend stores the value with the provided TTL, for Redis it's the SETEX command 
+        let SetValue { path, content, expiration, tags } = msg;
+        let set = self.backend.set(&path, content, expiration);
+        let tags: Vec<_> = tags.iter()
+            .map(|tag| self.backend.tag(tag, &path, expiration))
+            .collect();
+        self.guard(Box::new(set.join(future::join_all(tags)).map(|_| ())))
+    }
+}
+
+//  Entries can be invalidated one by one, by a common prefix of their keys or by a tag 
+#[derive(Clone, Debug)]
+pub enum Invalidation { 
+    Key(String),
+    Prefix(String),
+    Tag(String),
+}
+
+//  This message removes entries from the cache and returns how many were removed 
+struct Delete(Invalidation);
+
+impl Message for Delete { 
+    type Result = Result<usize, Error>;
+}
+impl Handler<Delete> for CacheActor { 
+    type Result = ResponseActFuture<Self, usize, Error>;
+
+    fn handle(&mut self, msg: Delete, _: &mut Self::Context) -> Self::Result { 
+        //  An invalidation that can't be applied is reported to the caller 
+        if !self.breaker.allow() { 
+            return Box::new(fut::err(format_err!("Cache backend is unavailable")));
+        }
+        let reply = match msg.0 { 
+            Invalidation::Key(key) => self.backend.delete(&key),
+            Invalidation::Prefix(prefix) => self.backend.delete_prefix(&prefix),
+            Invalidation::Tag(tag) => self.backend.delete_tag(&tag),
+        };
+        self.guard(reply)
     }
 }
 
@@ -52,14 +161,98 @@ struct GetValue {
 }
 //  Get value message 
 impl Message for GetValue { 
-    type Result = Result<Option<Vec<u8>>, RedisError>; 
+    type Result = Result<Option<Vec<u8>>, Error>; 
 }
 impl Handler<GetValue> for CacheActor { 
-    type Result = Result<Option<Vec<u8>>, RedisError>;
+    type Result = ResponseActFuture<Self, Option<Vec<u8>>, Error>;
 
-    //  CacheActor also implements a Handler trait for the GetValue message type, and uses the GET command of Redis Storage by calling the get method of Client to extract a value from storage: 
+    //  CacheActor also implements a Handler trait for the GetValue message type, and asks the backend to extract a value from storage: 
+    //  A failed read is a miss, the value is fetched from the upstream 
     fn handle(&mut self, msg: GetValue, _: &mut Self::Context) -> Self::Result { 
-        self.client.get(&msg.path)
+        if !self.breaker.allow() { 
+            return Box::new(fut::ok(None));
+        }
+        let reply = self.backend.get(&msg.path);
+        let fut = self.guard(reply)
+            .then(|res, _, _| fut::ok(res.unwrap_or(None)));
+        Box::new(fut)
+    }
+}
+
+//  Returns the counters of the breaker 
+struct GetStats;
+
+impl Message for GetStats { 
+    type Result = BreakerStats;
+}
+impl Handler<GetStats> for CacheActor { 
+    type Result = MessageResult<GetStats>;
+
+    fn handle(&mut self, _: GetStats, _: &mut Self::Context) -> Self::Result { 
+        MessageResult(self.breaker.stats())
+    }
+}
+//  Reads an entry together with its remaining TTL, the admin API shows them 
+struct Inspect { 
+    pub key: String,
+}
+
+impl Message for Inspect { 
+    type Result = Result<Option<KeyInfo>, Error>;
+}
+impl Handler<Inspect> for CacheActor { 
+    type Result = ResponseActFuture<Self, Option<KeyInfo>, Error>;
+
+    fn handle(&mut self, msg: Inspect, _: &mut Self::Context) -> Self::Result { 
+        if !self.breaker.allow() { 
+            return Box::new(fut::err(format_err!("Cache backend is unavailable")));
+        }
+        let Inspect { key } = msg;
+        let fut = self.backend.get(&key)
+            .join(self.backend.ttl(&key))
+            .map(move |(data, ttl)| data.map(|data| KeyInfo::new(key, &data, ttl)));
+        self.guard(Box::new(fut))
+    }
+}
+
+//  Counts how a request of a cached path was answered, it's sent for every request so nobody waits for it 
+struct Record { 
+    pub path: String,
+    pub outcome: Outcome,
+}
+
+impl Message for Record { 
+    type Result = ();
+}
+impl Handler<Record> for CacheActor { 
+    type Result = ();
+
+    fn handle(&mut self, msg: Record, _: &mut Self::Context) -> Self::Result { 
+        self.paths.entry(msg.path).or_default().record(msg.outcome);
+    }
+}
+
+//  Returns the counters of every path, sorted by path 
+struct GetPathStats;
+
+impl Message for GetPathStats { 
+    type Result = BTreeMap<String, PathStats>;
+}
+impl Handler<GetPathStats> for CacheActor { 
+    type Result = MessageResult<GetPathStats>;
+
+    fn handle(&mut self, _: GetPathStats, _: &mut Self::Context) -> Self::Result { 
+        MessageResult(self.paths.iter().map(|(path, stats)| (path.clone(), stats.clone())).collect())
+    }
+}
+//  The configuration can be reloaded at runtime, CacheActor passes the new size limit to the backend 
+impl Handler<ConfigUpdate> for CacheActor { 
+    type Result = ();
+
+    fn handle(&mut self, msg: ConfigUpdate, _: &mut Self::Context) -> Self::Result { 
+        let ConfigUpdate(config) = msg;
+        self.backend.resize(config.cache.max_bytes);
+        self.breaker.configure(config.cache.breaker.clone());
     }
 }
 //  We need a special type that allows methods to interact with the CacheActor instance 
@@ -83,22 +276,58 @@ impl CacheLink {
         };
         let fut = self.addr.send(msg)
             .from_err::<Error>()
-            .and_then(|x| x.map_err(Error::from));
+            .and_then(|x| x);
         Box::new(fut)
     //  The function returns this interaction sequence as a boxed Future
     }
     //  The next method is jmplemented in a similar way -- the set_value method sets a new value to a cache by sending a SetValue message to CacheActor
-    pub fn set_value(&self, path: &str, value: &[u8]) -> Box<dyn Future<Item = (), Error = Error>> { 
+    pub fn set_value(&self, path: &str, value: &[u8], expiration: usize, tags: &[String]) -> Box<dyn Future<Item = (), Error = Error>> { 
         let msg = SetValue { 
             path: path.to_owned(),
             content: value.to_owned(),
+            expiration,
+            tags: tags.to_owned(),
         };
         let fut = self.addr.send(msg)
             .from_err::<Error>()
-            .and_then(|x| x.map_err(Error::from));
+            .and_then(|x| x);
         Box::new(fut)
     }   
 
+    pub fn stats(&self) -> Box<dyn Future<Item = BreakerStats, Error = Error>> { 
+        Box::new(self.addr.send(GetStats).from_err())
+    }
+
+    //  The invalidate method removes a key, all keys with a prefix or all keys with a tag 
+    pub fn invalidate(&self, target: Invalidation) -> Box<dyn Future<Item = usize, Error = Error>> { 
+        let fut = self.addr.send(Delete(target))
+            .from_err::<Error>()
+            .and_then(|x| x);
+        Box::new(fut)
+    }
+
+    //  Size, remaining TTL and age of a stored entry, None when the key doesn't exist 
+    pub fn inspect(&self, key: &str) -> Box<dyn Future<Item = Option<KeyInfo>, Error = Error>> { 
+        let msg = Inspect { 
+            key: key.to_owned(),
+        };
+        let fut = self.addr.send(msg)
+            .from_err::<Error>()
+            .and_then(|x| x);
+        Box::new(fut)
+    }
+
+    pub fn record(&self, path: &str, outcome: Outcome) { 
+        self.addr.do_send(Record { 
+            path: path.to_owned(),
+            outcome,
+        });
+    }
+
+    pub fn path_stats(&self) -> Box<dyn Future<Item = BTreeMap<String, PathStats>, Error = Error>> { 
+        Box::new(self.addr.send(GetPathStats).from_err())
+    }
+
 }
 
 
@@ -0,0 +1,324 @@
+//  Cache backends
+//  CacheActor doesn't talk to Redis directly, it uses any storage that implements the CacheBackend trait
+//  Operations return futures, so a slow backend never blocks the actor while it waits for a reply
+use actix::prelude::*;
+use actix_redis::{Command, RedisActor, RespValue};
+use failure::{format_err, Error};
+use futures::Future;
+use futures::future::{self, Loop};
+use log::info;
+use redis::IntoConnectionInfo;
+use redis::ConnectionAddr;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::{Arc, RwLock};
+use super::cluster::Cluster;
+
+pub type Reply<T> = Box<dyn Future<Item = T, Error = Error>>;
+
+//  Wraps the result of an operation that finished right away
+pub fn ready<T: 'static>(res: Result<T, Error>) -> Reply<T> {
+    Box::new(future::result(res))
+}
+
+pub trait CacheBackend {
+    fn get(&mut self, key: &str) -> Reply<Option<Vec<u8>>>;
+
+    //  Stores a value that expires after ttl seconds
+    fn set(&mut self, key: &str, value: Vec<u8>, ttl: usize) -> Reply<()>;
+
+    //  Seconds until the entry expires, None when it doesn't exist
+    fn ttl(&mut self, key: &str) -> Reply<Option<u64>>;
+
+    //  Removes entries and returns how many of them existed
+    fn delete(&mut self, key: &str) -> Reply<usize>;
+
+    fn delete_prefix(&mut self, prefix: &str) -> Reply<usize>;
+
+    //  Tags group keys that have to be invalidated together, a tag lives at least as long as its keys
+    fn tag(&mut self, tag: &str, key: &str, ttl: usize) -> Reply<()>;
+
+    fn delete_tag(&mut self, tag: &str) -> Reply<usize>;
+
+    //  Called with the new size limit when the configuration is reloaded, backends without a limit ignore it
+    fn resize(&mut self, _max_bytes: usize) { }
+}
+
+//  Keys of tag sets in Redis
+fn tag_key(tag: &str) -> String {
+    format!("tag:{}", tag)
+}
+
+//  Escapes the glob characters of SCAN MATCH, so the prefix is matched literally
+fn pattern(prefix: &str) -> String {
+    let mut pattern = String::new();
+    for c in prefix.chars() {
+        if let '*' | '?' | '[' | ']' | '\\' = c {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('*');
+    pattern
+}
+
+//  Address of the Redis server for the connection actors, they only speak to database 0 without authentication
+pub fn redis_address(url: &str) -> Result<String, Error> {
+    let info = url.into_connection_info()?;
+    if info.redis.db != 0 || info.redis.password.is_some() {
+        return Err(format_err!("Redis address {} can't select a database or authenticate", url));
+    }
+    match info.addr {
+        ConnectionAddr::Tcp(host, port) => Ok(format!("{}:{}", host, port)),
+        _ => Err(format_err!("Redis address {} must be a TCP address", url)),
+    }
+}
+
+//  Builds a command from its name and arguments
+pub fn command<I, A>(args: I) -> Command
+    where
+        I: IntoIterator<Item = A>,
+        A: AsRef<[u8]>, {
+    let args = args.into_iter()
+        .map(|arg| RespValue::BulkString(arg.as_ref().to_vec()))
+        .collect();
+    Command(RespValue::Array(args))
+}
+
+//  Conversions of the replies, an error reply becomes an error of the future
+pub fn check(value: RespValue) -> Result<RespValue, Error> {
+    match value {
+        RespValue::Error(err) => Err(format_err!("Redis error {}", err)),
+        value => Ok(value),
+    }
+}
+
+pub fn bytes(value: RespValue) -> Result<Option<Vec<u8>>, Error> {
+    match check(value)? {
+        RespValue::Nil => Ok(None),
+        RespValue::BulkString(data) => Ok(Some(data)),
+        value => Err(format_err!("Unexpected Redis reply {:?}", value)),
+    }
+}
+
+pub fn integer(value: RespValue) -> Result<i64, Error> {
+    match check(value)? {
+        RespValue::Integer(n) => Ok(n),
+        value => Err(format_err!("Unexpected Redis reply {:?}", value)),
+    }
+}
+
+pub fn strings(value: RespValue) -> Result<Vec<String>, Error> {
+    match check(value)? {
+        RespValue::Array(values) => values.into_iter()
+            .map(|value| bytes(value)?
+                .map(|data| String::from_utf8_lossy(&data).into_owned())
+                .ok_or_else(|| format_err!("Unexpected Redis reply")))
+            .collect(),
+        value => Err(format_err!("Unexpected Redis reply {:?}", value)),
+    }
+}
+
+//  Sends a command over a connection, errors of the connection and error replies fail the future
+pub fn request(connection: &Addr<RedisActor>, command: Command) -> Reply<RespValue> {
+    let fut = connection.send(command)
+        .from_err::<Error>()
+        .and_then(|res| res.map_err(Error::from));
+    Box::new(fut)
+}
+
+//  Addresses of the Redis nodes, shared by the cache connections, the publisher and the subscriber
+//  A single server has one address that the SentinelActor replaces when the master fails over, a cluster lists its seeds
+#[derive(Clone)]
+pub struct RedisNodes(Arc<RwLock<Vec<String>>>);
+
+impl RedisNodes {
+    pub fn new(nodes: Vec<String>) -> Self {
+        RedisNodes(Arc::new(RwLock::new(nodes)))
+    }
+
+    pub fn get(&self) -> Vec<String> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub fn replace(&self, nodes: Vec<String>) {
+        *self.0.write().unwrap() = nodes;
+    }
+}
+
+//  Connections to a single server, they are opened again when the address of the server changes
+struct Pool {
+    nodes: RedisNodes,
+    address: Option<String>,
+    connections: Vec<Addr<RedisActor>>,
+    size: usize,
+    next: usize,
+}
+
+impl Pool {
+    fn connection(&mut self) -> Option<Addr<RedisActor>> {
+        let address = self.nodes.get().into_iter().next();
+        if address != self.address {
+            let size = self.size;
+            self.connections = address.iter()
+                .flat_map(|address| (0..size).map(move |_| RedisActor::start(address.clone())))
+                .collect();
+            if let Some(address) = &address {
+                info!("Cache uses the Redis server at {}", address);
+            }
+            self.address = address;
+        }
+        if self.connections.is_empty() {
+            return None;
+        }
+        self.next = self.next.wrapping_add(1);
+        Some(self.connections[self.next % self.connections.len()].clone())
+    }
+}
+
+//  Commands go to a pool of connections to one server, or to the node that owns the slot of the key in a cluster
+#[derive(Clone)]
+enum Topology {
+    Server(Rc<RefCell<Pool>>),
+    Cluster(Cluster),
+}
+
+//  Redis backend, it uses the GET and SETEX commands of the Redis storage
+//  Every RedisActor pipelines commands over one connection and reconnects with an exponential backoff when it breaks
+#[derive(Clone)]
+pub struct RedisBackend {
+    topology: Topology,
+}
+
+impl RedisBackend {
+    //  Commands are spread over the connections to the first of the nodes
+    pub fn new(nodes: RedisNodes, connections: usize) -> Self {
+        let pool = Pool {
+            nodes,
+            address: None,
+            connections: Vec::new(),
+            size: connections.max(1),
+            next: 0,
+        };
+        Self { topology: Topology::Server(Rc::new(RefCell::new(pool))) }
+    }
+
+    pub fn cluster(seeds: Vec<String>) -> Self {
+        Self { topology: Topology::Cluster(Cluster::new(seeds)) }
+    }
+
+    pub fn send(&self, key: &str, command: Command) -> Reply<RespValue> {
+        match &self.topology {
+            Topology::Server(pool) => match pool.borrow_mut().connection() {
+                Some(connection) => request(&connection, command),
+                None => ready(Err(format_err!("Address of the Redis master isn't known yet"))),
+            },
+            Topology::Cluster(cluster) => cluster.send(key, command),
+        }
+    }
+
+    //  Connections to all nodes that hold keys
+    fn masters(&self) -> Reply<Vec<Addr<RedisActor>>> {
+        match &self.topology {
+            Topology::Server(pool) => ready(Ok(pool.borrow_mut().connection().into_iter().collect())),
+            Topology::Cluster(cluster) => cluster.masters(),
+        }
+    }
+
+    //  Deletes the keys and returns how many of them existed, keys of a cluster can live on different nodes so each one is deleted alone
+    fn delete_keys(&self, keys: Vec<String>) -> Reply<usize> {
+        let deletes: Vec<_> = keys.iter()
+            .map(|key| self.send(key, command(vec!["DEL", key])).and_then(integer))
+            .collect();
+        Box::new(future::join_all(deletes).map(|counts| counts.into_iter().sum::<i64>() as usize))
+    }
+}
+
+//  Collects all keys of a node matching a pattern, SCAN returns them in batches until the cursor is back at 0
+fn scan(connection: Addr<RedisActor>, pattern: String) -> Reply<Vec<String>> {
+    let fut = future::loop_fn((String::from("0"), Vec::new()), move |(cursor, mut keys)| {
+        request(&connection, command(vec!["SCAN", &cursor, "MATCH", &pattern, "COUNT", "1000"]))
+            .and_then(move |reply| {
+                let (cursor, batch) = match check(reply)? {
+                    RespValue::Array(mut parts) if parts.len() == 2 => {
+                        let batch = strings(parts.pop().unwrap())?;
+                        let cursor = bytes(parts.pop().unwrap())?.unwrap_or_default();
+                        (String::from_utf8_lossy(&cursor).into_owned(), batch)
+                    }
+                    value => return Err(format_err!("Unexpected Redis reply {:?}", value)),
+                };
+                keys.extend(batch);
+                if cursor == "0" {
+                    Ok(Loop::Break(keys))
+                } else {
+                    Ok(Loop::Continue((cursor, keys)))
+                }
+            })
+    });
+    Box::new(fut)
+}
+
+impl CacheBackend for RedisBackend {
+    fn get(&mut self, key: &str) -> Reply<Option<Vec<u8>>> {
+        Box::new(self.send(key, command(vec!["GET", key])).and_then(bytes))
+    }
+
+    fn set(&mut self, key: &str, value: Vec<u8>, ttl: usize) -> Reply<()> {
+        let ttl = ttl.to_string();
+        let args: Vec<&[u8]> = vec![b"SETEX", key.as_bytes(), ttl.as_bytes(), &value];
+        Box::new(self.send(key, command(args)).and_then(check).map(|_| ()))
+    }
+
+    //  TTL answers -2 for a missing key and -1 for a key without expiry, cached keys always have one
+    fn ttl(&mut self, key: &str) -> Reply<Option<u64>> {
+        let fut = self.send(key, command(vec!["TTL", key]))
+            .and_then(integer)
+            .map(|ttl| if ttl >= 0 { Some(ttl as u64) } else { None });
+        Box::new(fut)
+    }
+
+    fn delete(&mut self, key: &str) -> Reply<usize> {
+        self.delete_keys(vec![key.to_owned()])
+    }
+
+    fn delete_prefix(&mut self, prefix: &str) -> Reply<usize> {
+        let backend = self.clone();
+        let pattern = pattern(prefix);
+        let fut = self.masters()
+            .and_then(move |masters| {
+                future::join_all(masters.into_iter().map(move |master| scan(master, pattern.clone())))
+            })
+            .and_then(move |keys| backend.delete_keys(keys.into_iter().flatten().collect()));
+        Box::new(fut)
+    }
+
+    fn tag(&mut self, tag: &str, key: &str, ttl: usize) -> Reply<()> {
+        let tag = tag_key(tag);
+        let current = self.send(&tag, command(vec!["TTL", &tag])).and_then(integer);
+        let add = self.send(&tag, command(vec!["SADD", &tag, key])).and_then(check);
+        //  The tag is only extended, it must not expire before the longest living of its keys
+        let expire = command(vec!["EXPIRE".to_owned(), tag.clone(), ttl.to_string()]);
+        let backend = self.clone();
+        let fut = current.join(add)
+            .and_then(move |(current, _)| {
+                if current < ttl as i64 {
+                    Box::new(backend.send(&tag, expire).and_then(check).map(|_| ())) as Reply<()>
+                } else {
+                    ready(Ok(()))
+                }
+            });
+        Box::new(fut)
+    }
+
+    fn delete_tag(&mut self, tag: &str) -> Reply<usize> {
+        let tag = tag_key(tag);
+        let backend = self.clone();
+        let fut = self.send(&tag, command(vec!["SMEMBERS", &tag]))
+            .and_then(strings)
+            .and_then(move |keys| {
+                let removed = backend.delete_keys(keys);
+                backend.delete_keys(vec![tag]).join(removed).map(|(_, removed)| removed)
+            });
+        Box::new(fut)
+    }
+}
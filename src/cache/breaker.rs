@@ -0,0 +1,153 @@
+//  Circuit breaker
+//  When the backend fails several times in a row, the cache stops sending commands to it for a while and every read is a miss,
+//  requests go straight to the upstreams instead of waiting for a backend that is down
+//  After the cooldown the next command is let through, it closes the breaker again if it succeeds
+use log::{info, warn};
+use serde_derive::Serialize;
+use std::time::{Duration, Instant};
+use crate::config::BreakerConfig;
+
+pub struct Breaker {
+    config: BreakerConfig,
+    failures: u32,
+    open_until: Option<Instant>,
+    stats: BreakerStats,
+}
+
+//  Counters of the cache backend, served on /stats/cache
+#[derive(Clone, Default, Serialize)]
+pub struct BreakerStats {
+    pub errors: usize,
+    //  Commands that weren't sent because the breaker was open
+    pub skipped: usize,
+    //  How many times the breaker opened
+    pub trips: usize,
+    pub open: bool,
+}
+
+impl Breaker {
+    pub fn new(config: BreakerConfig) -> Self {
+        Self {
+            config,
+            failures: 0,
+            open_until: None,
+            stats: BreakerStats::default(),
+        }
+    }
+
+    pub fn configure(&mut self, config: BreakerConfig) {
+        self.config = config;
+    }
+
+    //  Tells whether a command can be sent to the backend
+    pub fn allow(&mut self) -> bool {
+        match self.open_until {
+            Some(until) if Instant::now() < until => {
+                self.stats.skipped += 1;
+                false
+            }
+            _ => true,
+        }
+    }
+
+    pub fn success(&mut self) {
+        if self.open_until.take().is_some() {
+            info!("Cache backend is available again");
+        }
+        self.failures = 0;
+    }
+
+    pub fn failure(&mut self) {
+        self.stats.errors += 1;
+        self.failures += 1;
+        if self.config.failures > 0 && self.failures >= self.config.failures {
+            if self.open_until.is_none() {
+                self.stats.trips += 1;
+                warn!("Cache backend failed {} times, skipping it for {}s", self.failures, self.config.cooldown);
+            }
+            self.open_until = Some(Instant::now() + Duration::from_secs(self.config.cooldown));
+        }
+    }
+
+    pub fn stats(&self) -> BreakerStats {
+        let mut stats = self.stats.clone();
+        stats.open = self.open_until.map(|until| Instant::now() < until).unwrap_or(false);
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn breaker(failures: u32) -> Breaker {
+        Breaker::new(BreakerConfig { failures, cooldown: 60 })
+    }
+
+    //  Ends the cooldown without waiting for it
+    fn cool_down(breaker: &mut Breaker) {
+        breaker.open_until = Some(Instant::now());
+    }
+
+    #[test]
+    fn trips_after_the_failures_in_a_row() {
+        let mut breaker = breaker(3);
+        breaker.failure();
+        breaker.failure();
+        assert!(breaker.allow());
+        //  A success starts the count over
+        breaker.success();
+        breaker.failure();
+        breaker.failure();
+        assert!(breaker.allow());
+        breaker.failure();
+        let stats = breaker.stats();
+        assert!(stats.open);
+        assert_eq!((stats.errors, stats.trips), (5, 1));
+    }
+
+    #[test]
+    fn skips_commands_during_the_cooldown() {
+        let mut breaker = breaker(1);
+        breaker.failure();
+        assert!(!breaker.allow());
+        assert!(!breaker.allow());
+        assert_eq!(breaker.stats().skipped, 2);
+    }
+
+    #[test]
+    fn successful_probe_closes_it() {
+        let mut breaker = breaker(2);
+        breaker.failure();
+        breaker.failure();
+        cool_down(&mut breaker);
+        assert!(breaker.allow());
+        breaker.success();
+        assert!(!breaker.stats().open);
+        //  The count starts over, one failure doesn't trip it again
+        breaker.failure();
+        assert!(breaker.allow());
+    }
+
+    #[test]
+    fn failed_probe_opens_it_again() {
+        let mut breaker = breaker(2);
+        breaker.failure();
+        breaker.failure();
+        cool_down(&mut breaker);
+        assert!(breaker.allow());
+        breaker.failure();
+        assert!(!breaker.allow());
+        assert!(breaker.stats().open);
+    }
+
+    #[test]
+    fn zero_failures_disables_it() {
+        let mut breaker = breaker(0);
+        for _ in 0..10 {
+            breaker.failure();
+        }
+        assert!(breaker.allow());
+        assert_eq!(breaker.stats().trips, 0);
+    }
+}
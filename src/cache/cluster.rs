@@ -0,0 +1,242 @@
+//  Redis Cluster
+//  Keys are spread over 16384 slots and every master node owns some ranges of them. The slot map is loaded with CLUSTER SLOTS
+//  from the seeds or any known node, and loaded again when a node answers MOVED or a connection fails during a failover
+use actix::prelude::*;
+use actix_redis::{Command, RedisActor, RespValue};
+use failure::{format_err, Error};
+use futures::Future;
+use futures::future::{self, Loop};
+use log::{info, warn};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use super::backend::{bytes, command, integer, ready, request, Reply};
+
+const SLOTS: u16 = 16384;
+
+//  CRC16/XMODEM of the key, or of its hash tag when the key has one, like {user42}:profile
+pub fn slot(key: &str) -> u16 {
+    let mut key = key.as_bytes();
+    if let Some(open) = key.iter().position(|b| *b == b'{') {
+        if let Some(len) = key[open + 1..].iter().position(|b| *b == b'}') {
+            if len > 0 {
+                key = &key[open + 1..open + 1 + len];
+            }
+        }
+    }
+    let mut crc: u16 = 0;
+    for byte in key {
+        crc ^= u16::from(*byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc % SLOTS
+}
+
+struct State {
+    seeds: Vec<String>,
+    connections: HashMap<String, Addr<RedisActor>>,
+    //  Ranges of slots with the address of their master
+    slots: Vec<(u16, u16, String)>,
+    refreshing: bool,
+}
+
+#[derive(Clone)]
+pub struct Cluster(Rc<RefCell<State>>);
+
+impl Cluster {
+    pub fn new(seeds: Vec<String>) -> Self {
+        let state = State {
+            seeds,
+            connections: HashMap::new(),
+            slots: Vec::new(),
+            refreshing: false,
+        };
+        Cluster(Rc::new(RefCell::new(state)))
+    }
+
+    fn connection(&self, address: &str) -> Addr<RedisActor> {
+        self.0.borrow_mut().connections.entry(address.to_owned())
+            .or_insert_with(|| RedisActor::start(address.to_owned()))
+            .clone()
+    }
+
+    fn owner(&self, slot: u16) -> Option<String> {
+        self.0.borrow().slots.iter()
+            .find(|(start, end, _)| *start <= slot && slot <= *end)
+            .map(|(_, _, address)| address.clone())
+    }
+
+    //  Asks the known masters and then the seeds for the slot map, the first answer is used
+    fn refresh(&self) -> Reply<()> {
+        let candidates = {
+            let state = self.0.borrow();
+            let mut candidates: Vec<String> = state.slots.iter().map(|(_, _, address)| address.clone()).collect();
+            candidates.extend(state.seeds.iter().cloned());
+            candidates.dedup();
+            candidates
+        };
+        let cluster = self.clone();
+        let fut = future::loop_fn(0, move |idx| {
+            let address = match candidates.get(idx) {
+                Some(address) => address.clone(),
+                None => return ready(Err(format_err!("No Redis cluster node answered CLUSTER SLOTS"))),
+            };
+            let cluster = cluster.clone();
+            let fut = request(&cluster.connection(&address), command(vec!["CLUSTER", "SLOTS"]))
+                .and_then(move |reply| parse_slots(reply, &address))
+                .then(move |res| match res {
+                    Ok(slots) => {
+                        let mut state = cluster.0.borrow_mut();
+                        if state.slots != slots {
+                            info!("Redis cluster slot map loaded: {} ranges", slots.len());
+                        }
+                        state.slots = slots;
+                        Ok(Loop::Break(()))
+                    }
+                    Err(err) => {
+                        warn!("Can't load the slot map from a Redis cluster node: {}", err);
+                        Ok(Loop::Continue(idx + 1))
+                    }
+                });
+            Box::new(fut) as Reply<Loop<(), usize>>
+        });
+        Box::new(fut)
+    }
+
+    //  Loads the slot map in the background, only one refresh runs at a time
+    fn refresh_later(&self) {
+        if self.0.borrow().refreshing {
+            return;
+        }
+        self.0.borrow_mut().refreshing = true;
+        let cluster = self.clone();
+        Arbiter::spawn(self.refresh().then(move |_| {
+            cluster.0.borrow_mut().refreshing = false;
+            Ok(())
+        }));
+    }
+
+    //  Sends a command to the owner of the slot of the key, following one redirection
+    pub fn send(&self, key: &str, cmd: Command) -> Reply<RespValue> {
+        let slot = slot(key);
+        let route = match self.owner(slot) {
+            Some(address) => ready(Ok(address)),
+            None => {
+                let cluster = self.clone();
+                let fut = self.refresh().and_then(move |_| {
+                    cluster.owner(slot).ok_or_else(|| format_err!("No Redis cluster node owns slot {}", slot))
+                });
+                Box::new(fut)
+            }
+        };
+        let cluster = self.clone();
+        let fut = route.and_then(move |address| {
+            let value = cmd.0;
+            request(&cluster.connection(&address), Command(value.clone()))
+                .then(move |res| match res {
+                    //  The slot moved to another node for good, the map is outdated
+                    Ok(RespValue::Error(ref err)) if err.starts_with("MOVED ") => {
+                        cluster.refresh_later();
+                        match err.split(' ').nth(2) {
+                            Some(address) => request(&cluster.connection(address), Command(value)),
+                            None => ready(Err(format_err!("Invalid redirection {}", err))),
+                        }
+                    }
+                    //  The slot is being migrated, only this command goes to the other node
+                    Ok(RespValue::Error(ref err)) if err.starts_with("ASK ") => {
+                        match err.split(' ').nth(2) {
+                            Some(address) => {
+                                let connection = cluster.connection(address);
+                                let asking = request(&connection, command(vec!["ASKING"]));
+                                let fut = request(&connection, Command(value));
+                                Box::new(asking.then(|_| fut))
+                            }
+                            None => ready(Err(format_err!("Invalid redirection {}", err))),
+                        }
+                    }
+                    //  A failed connection can mean the master is gone and a replica took over its slots
+                    Err(err) => {
+                        cluster.refresh_later();
+                        ready(Err(err))
+                    }
+                    res => ready(res),
+                })
+        });
+        Box::new(fut)
+    }
+
+    //  Connections to all masters, SCAN has to visit each of them
+    pub fn masters(&self) -> Reply<Vec<Addr<RedisActor>>> {
+        let cluster = self.clone();
+        let loaded = if self.0.borrow().slots.is_empty() { self.refresh() } else { ready(Ok(())) };
+        let fut = loaded.map(move |_| {
+            let mut addresses: Vec<String> = cluster.0.borrow().slots.iter().map(|(_, _, address)| address.clone()).collect();
+            addresses.sort();
+            addresses.dedup();
+            addresses.iter().map(|address| cluster.connection(address)).collect()
+        });
+        Box::new(fut)
+    }
+}
+
+//  CLUSTER SLOTS answers with [start, end, [host, port, id], replicas...] for every range
+//  An empty host means the node that answered
+fn parse_slots(reply: RespValue, from: &str) -> Result<Vec<(u16, u16, String)>, Error> {
+    let ranges = match reply {
+        RespValue::Array(ranges) => ranges,
+        value => return Err(format_err!("Unexpected Redis reply {:?}", value)),
+    };
+    let default_host = from.rsplit_once(':').map(|(host, _)| host).unwrap_or(from).to_owned();
+    let mut slots = Vec::new();
+    for range in ranges {
+        let mut parts = match range {
+            RespValue::Array(parts) if parts.len() >= 3 => parts.into_iter(),
+            value => return Err(format_err!("Unexpected Redis reply {:?}", value)),
+        };
+        let start = integer(parts.next().unwrap())? as u16;
+        let end = integer(parts.next().unwrap())? as u16;
+        let mut master = match parts.next().unwrap() {
+            RespValue::Array(master) if master.len() >= 2 => master.into_iter(),
+            value => return Err(format_err!("Unexpected Redis reply {:?}", value)),
+        };
+        let host = bytes(master.next().unwrap())?
+            .map(|host| String::from_utf8_lossy(&host).into_owned())
+            .filter(|host| !host.is_empty())
+            .unwrap_or_else(|| default_host.clone());
+        let port = integer(master.next().unwrap())?;
+        slots.push((start, end, format!("{}:{}", host, port)));
+    }
+    if slots.is_empty() {
+        return Err(format_err!("The cluster has no slots assigned"));
+    }
+    slots.sort();
+    Ok(slots)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slots_match_redis() {
+        //  CRC16 XMODEM of the check string is 0x31C3
+        assert_eq!(slot("123456789"), 0x31C3 % SLOTS);
+        assert_eq!(slot("123456789"), 12739);
+        assert_eq!(slot("foo"), 12182);
+        assert_eq!(slot("bar"), 5061);
+        assert_eq!(slot("hello"), 866);
+        assert_eq!(slot(""), 0);
+    }
+
+    #[test]
+    fn hash_tags_select_the_hashed_part() {
+        assert_eq!(slot("{user1000}.following"), slot("user1000"));
+        assert_eq!(slot("{user1000}.followers"), slot("{user1000}.following"));
+        //  Only the first tag counts, and an empty tag hashes the whole key
+        assert_eq!(slot("foo{bar}{zap}"), slot("bar"));
+        assert_eq!(slot("foo{{bar}}zap"), slot("{bar"));
+        assert_eq!(slot("foo{}{bar}"), 8363);
+    }
+}
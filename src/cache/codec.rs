@@ -0,0 +1,74 @@
+//  Compression of cached bodies
+//  Large lists take a lot of memory in Redis, bodies above the configured threshold are compressed before they are stored
+//  The encoding is written into the header of the entry, so entries stored with another setting can still be read
+use failure::{format_err, Error};
+use flate2::Compression as Level;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use std::io::{Read, Write};
+use crate::config::Compression;
+
+//  Returns the name of the encoding and the compressed body, or None when the body is stored as it is
+pub fn compress(kind: Compression, threshold: usize, body: &[u8]) -> Result<Option<(String, Vec<u8>)>, Error> {
+    if body.len() < threshold {
+        return Ok(None);
+    }
+    let res = match kind {
+        Compression::None => return Ok(None),
+        Compression::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Level::default());
+            encoder.write_all(body)?;
+            ("gzip", encoder.finish()?)
+        }
+        Compression::Zstd => ("zstd", zstd::encode_all(body, 0)?),
+    };
+    //  Bodies that don't get smaller aren't worth decompressing on every hit
+    if res.1.len() >= body.len() {
+        return Ok(None);
+    }
+    Ok(Some((res.0.to_owned(), res.1)))
+}
+
+pub fn decompress(encoding: &str, data: &[u8]) -> Result<Vec<u8>, Error> {
+    match encoding {
+        "gzip" => {
+            let mut body = Vec::new();
+            GzDecoder::new(data).read_to_end(&mut body)?;
+            Ok(body)
+        }
+        "zstd" => Ok(zstd::decode_all(data)?),
+        _ => Err(format_err!("Unknown encoding {}", encoding)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body() -> Vec<u8> {
+        "[{\"uid\":\"42\",\"text\":\"hello\"}]".repeat(100).into_bytes()
+    }
+
+    #[test]
+    fn bodies_survive_a_round_trip() {
+        for kind in &[Compression::Gzip, Compression::Zstd] {
+            let (encoding, data) = compress(*kind, 16, &body()).unwrap().unwrap();
+            assert!(data.len() < body().len());
+            assert_eq!(decompress(&encoding, &data).unwrap(), body());
+        }
+    }
+
+    #[test]
+    fn small_and_incompressible_bodies_are_kept() {
+        assert!(compress(Compression::Gzip, body().len() + 1, &body()).unwrap().is_none());
+        assert!(compress(Compression::None, 0, &body()).unwrap().is_none());
+        assert!(compress(Compression::Zstd, 0, b"x").unwrap().is_none());
+    }
+
+    #[test]
+    fn unknown_and_corrupt_data_fail() {
+        assert!(decompress("br", b"data").is_err());
+        assert!(decompress("gzip", b"not gzip").is_err());
+        assert!(decompress("zstd", b"not zstd").is_err());
+    }
+}
@@ -0,0 +1,203 @@
+//  Cache entries
+//  Values are stored with a small header: one line of JSON with the metadata, then the body as it is
+//  The backend removes an entry at its hard expiry, the header tells until when the entry is fresh
+//  and carries the validators (ETag and Last-Modified) for conditional requests
+//  The body can be stored compressed, the header tells its encoding and the content type of the upstream response
+use failure::Error;
+use openssl::sha::sha1;
+use serde_derive::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use super::codec;
+use crate::config::Compression;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Meta {
+    //  Unix timestamps in milliseconds
+    pub stored: u64,
+    pub fresh_until: u64,
+    //  Quoted hash of the body
+    pub etag: String,
+    //  Unix timestamp in seconds of the last change of the body
+    pub last_modified: u64,
+    #[serde(default)]
+    pub content_type: Option<String>,
+    //  Encoding of the stored body, entries in memory always keep it decompressed
+    #[serde(default)]
+    pub encoding: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct Entry {
+    pub meta: Meta,
+    pub body: Vec<u8>,
+    //  Set when the upstream didn't allow to store the response, it's never written to the cache
+    pub no_store: bool,
+}
+
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn etag(body: &[u8]) -> String {
+    let hash: String = sha1(body).iter().map(|b| format!("{:02x}", b)).collect();
+    format!("\"{}\"", hash)
+}
+
+impl Entry {
+    //  Creates an entry that is fresh for ttl seconds from now
+    //  If the body didn't change since the previous entry, the entry keeps its modification time
+    pub fn new(body: Vec<u8>, ttl: usize, previous: Option<&Meta>) -> Self {
+        let stored = now();
+        let etag = etag(&body);
+        let last_modified = match previous {
+            Some(meta) if meta.etag == etag => meta.last_modified,
+            _ => stored / 1000,
+        };
+        let meta = Meta {
+            stored,
+            fresh_until: stored + ttl as u64 * 1000,
+            etag,
+            last_modified,
+            content_type: None,
+            encoding: None,
+        };
+        Self {
+            meta,
+            body,
+            no_store: false,
+        }
+    }
+
+    //  Bodies of at least threshold bytes are compressed
+    pub fn encode(&self, compression: Compression, threshold: usize) -> Result<Vec<u8>, Error> {
+        let mut meta = self.meta.clone();
+        let compressed = codec::compress(compression, threshold, &self.body)?;
+        let body = match &compressed {
+            Some((encoding, body)) => {
+                meta.encoding = Some(encoding.clone());
+                body
+            }
+            None => &self.body,
+        };
+        let mut data = serde_json::to_vec(&meta)?;
+        data.push(b'\n');
+        data.extend_from_slice(body);
+        Ok(data)
+    }
+
+    //  Values in another format are treated as missing
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let split = data.iter().position(|b| *b == b'\n')?;
+        let mut meta: Meta = serde_json::from_slice(&data[..split]).ok()?;
+        let body = match meta.encoding.take() {
+            Some(encoding) => codec::decompress(&encoding, &data[split + 1..]).ok()?,
+            None => data[split + 1..].to_vec(),
+        };
+        Some(Self {
+            meta,
+            body,
+            no_store: false,
+        })
+    }
+
+    pub fn is_fresh(&self) -> bool {
+        now() < self.meta.fresh_until
+    }
+
+    //  Seconds passed since the entry became stale
+    pub fn stale_for(&self) -> u64 {
+        now().saturating_sub(self.meta.fresh_until) / 1000
+    }
+
+    //  Seconds the entry stays fresh
+    pub fn max_age(&self) -> u64 {
+        self.meta.fresh_until.saturating_sub(now()) / 1000
+    }
+
+    pub fn last_modified(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.meta.last_modified)
+    }
+}
+
+//  Cache directives of an upstream response that decide whether and how long we cache it
+#[derive(Clone, Default)]
+pub struct Directives {
+    pub no_store: bool,
+    pub max_age: Option<usize>,
+}
+
+impl Directives {
+    //  Parses a Cache-Control header, shared caches prefer s-maxage over max-age
+    pub fn parse(value: &str) -> Self {
+        let mut directives = Directives::default();
+        let mut s_maxage = None;
+        for directive in value.split(',') {
+            let mut parts = directive.trim().splitn(2, '=');
+            let name = parts.next().unwrap_or("").to_lowercase();
+            let arg = parts.next().and_then(|arg| arg.trim_matches('"').parse().ok());
+            match name.as_str() {
+                "no-store" | "no-cache" | "private" => directives.no_store = true,
+                "max-age" => directives.max_age = arg,
+                "s-maxage" => s_maxage = arg,
+                _ => { }
+            }
+        }
+        directives.max_age = s_maxage.or(directives.max_age);
+        directives
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_survive_a_round_trip() {
+        let body = b"[\"comment\"]".repeat(200);
+        let mut entry = Entry::new(body.clone(), 10, None);
+        entry.meta.content_type = Some("application/json".to_owned());
+        for kind in &[Compression::None, Compression::Gzip, Compression::Zstd] {
+            let data = entry.encode(*kind, 64).unwrap();
+            let decoded = Entry::decode(&data).unwrap();
+            assert_eq!(decoded.body, body);
+            assert_eq!(decoded.meta.etag, entry.meta.etag);
+            assert_eq!(decoded.meta.content_type.as_deref(), Some("application/json"));
+            //  The encoding only describes the stored body
+            assert_eq!(decoded.meta.encoding, None);
+        }
+        assert!(Entry::decode(b"not an entry").is_none());
+    }
+
+    #[test]
+    fn unchanged_bodies_keep_their_modification_time() {
+        let mut previous = Entry::new(b"[]".to_vec(), 10, None).meta;
+        previous.last_modified = 1000;
+        assert_eq!(Entry::new(b"[]".to_vec(), 10, Some(&previous)).meta.last_modified, 1000);
+        assert_ne!(Entry::new(b"[1]".to_vec(), 10, Some(&previous)).meta.last_modified, 1000);
+    }
+
+    #[test]
+    fn shared_max_age_wins() {
+        let directives = Directives::parse("public, max-age=60, s-maxage=30");
+        assert!(!directives.no_store);
+        assert_eq!(directives.max_age, Some(30));
+        assert_eq!(Directives::parse("max-age=\"60\"").max_age, Some(60));
+    }
+
+    #[test]
+    fn private_responses_are_not_stored() {
+        for value in &["no-store", "No-Cache", "private, max-age=60", "max-age=60 , private"] {
+            assert!(Directives::parse(value).no_store, "{}", value);
+        }
+    }
+
+    #[test]
+    fn invalid_ages_are_ignored() {
+        assert_eq!(Directives::parse("max-age=soon").max_age, None);
+        assert_eq!(Directives::parse("max-age").max_age, None);
+        assert_eq!(Directives::parse("").max_age, None);
+        assert!(!Directives::parse("").no_store);
+    }
+}
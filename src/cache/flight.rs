@@ -0,0 +1,179 @@
+//  Single-flight
+//  When a popular key expires, all concurrent requests miss at once. Only the first of them (the leader) fetches the value
+//  from the upstream, the others wait for the result of that fetch instead of sending their own requests
+//  The fetch runs on its own in the arbiter, so a leader whose client goes away doesn't take the waiters down with it
+use actix::Arbiter;
+use failure::{format_err, Error};
+use futures::{Future, sync::oneshot};
+use serde_derive::Serialize;
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+//  Errors can't be cloned, so every caller receives the message of the fetch's error
+type Waiter<T> = oneshot::Sender<Result<T, String>>;
+
+#[derive(Clone)]
+pub struct SingleFlight<T> {
+    waiting: Arc<Mutex<HashMap<String, Vec<Waiter<T>>>>>,
+    fetches: Arc<AtomicUsize>,
+    coalesced: Arc<AtomicUsize>,
+}
+
+impl<T> Default for SingleFlight<T> {
+    fn default() -> Self {
+        Self {
+            waiting: Arc::default(),
+            fetches: Arc::default(),
+            coalesced: Arc::default(),
+        }
+    }
+}
+
+//  How many fetches were sent to upstreams and how many requests reused the result of another one
+#[derive(Serialize)]
+pub struct FlightStats {
+    pub fetches: usize,
+    pub coalesced: usize,
+}
+
+impl<T: Clone + 'static> SingleFlight<T> {
+    //  Any error type that can be built from a failure error works, so handlers can pass futures with actix-web errors
+    pub fn run<F>(&self, key: &str, fut: F) -> Box<dyn Future<Item = T, Error = F::Error>>
+        where
+            F: Future<Item = T> + 'static,
+            F::Error: Display + From<Error>, {
+        let (tx, rx): (Waiter<T>, _) = oneshot::channel();
+        let result = rx
+            .map_err(|_| F::Error::from(format_err!("Upstream fetch was cancelled")))
+            .and_then(|res| res.map_err(|err| F::Error::from(format_err!("{}", err))));
+        let mut waiting = self.waiting.lock().unwrap();
+        if let Some(waiters) = waiting.get_mut(key) {
+            waiters.push(tx);
+            self.coalesced.fetch_add(1, Ordering::Relaxed);
+            return Box::new(result);
+        }
+        //  The leader waits for the result like everyone else
+        waiting.insert(key.to_owned(), vec![tx]);
+        drop(waiting);
+        self.fetches.fetch_add(1, Ordering::Relaxed);
+        let leader = Leader {
+            key: Some(key.to_owned()),
+            waiting: self.waiting.clone(),
+        };
+        Arbiter::spawn(fut.then(move |res| {
+            leader.finish(&res);
+            Ok(())
+        }));
+        Box::new(result)
+    }
+
+    pub fn stats(&self) -> FlightStats {
+        FlightStats {
+            fetches: self.fetches.load(Ordering::Relaxed),
+            coalesced: self.coalesced.load(Ordering::Relaxed),
+        }
+    }
+}
+
+//  Owns the key while the fetch is running. If the fetch is dropped before it completes (the arbiter stops),
+//  the key is released and the waiters get an error instead of waiting forever
+struct Leader<T> {
+    key: Option<String>,
+    waiting: Arc<Mutex<HashMap<String, Vec<Waiter<T>>>>>,
+}
+
+impl<T: Clone> Leader<T> {
+    fn finish<E: Display>(mut self, res: &Result<T, E>) {
+        let key = self.key.take().unwrap();
+        let waiters = self.waiting.lock().unwrap().remove(&key).unwrap_or_default();
+        for waiter in waiters {
+            let res = match res {
+                Ok(data) => Ok(data.clone()),
+                Err(err) => Err(err.to_string()),
+            };
+            waiter.send(res).ok();
+        }
+    }
+}
+
+impl<T> Drop for Leader<T> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.waiting.lock().unwrap().remove(&key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix::System;
+    use futures::future;
+
+    //  The fetch of a caller that joins a running one is never polled
+    fn unused() -> impl Future<Item = u32, Error = Error> {
+        future::lazy(|| -> Result<u32, Error> { panic!("A second fetch was started") })
+    }
+
+    fn upstream() -> (oneshot::Sender<u32>, impl Future<Item = u32, Error = Error>) {
+        let (tx, rx) = oneshot::channel();
+        (tx, rx.map_err(|_| format_err!("Upstream went away")))
+    }
+
+    #[test]
+    fn concurrent_callers_share_one_fetch() {
+        let flights = SingleFlight::default();
+        let (tx, fetch) = upstream();
+        let results = System::new("flight").block_on(future::lazy(|| {
+            let calls = vec![flights.run("k", fetch), flights.run("k", unused()), flights.run("k", unused())];
+            tx.send(7).unwrap();
+            future::join_all(calls)
+        }));
+        assert_eq!(results.unwrap(), vec![7, 7, 7]);
+        let stats = flights.stats();
+        assert_eq!((stats.fetches, stats.coalesced), (1, 2));
+        //  The key is released, the next miss fetches again
+        let result = System::new("flight").block_on(future::lazy(|| flights.run("k", future::ok::<u32, Error>(8))));
+        assert_eq!(result.unwrap(), 8);
+    }
+
+    #[test]
+    fn errors_reach_every_waiter() {
+        let flights = SingleFlight::<u32>::default();
+        let results = System::new("flight").block_on(future::lazy(|| {
+            let leader = flights.run("k", future::err::<u32, Error>(format_err!("Microservice error: 503")));
+            let waiter = flights.run("k", unused());
+            leader.then(Ok::<_, Error>).join(waiter.then(Ok::<_, Error>))
+        }));
+        let (leader, waiter) = results.unwrap();
+        for res in &[leader, waiter] {
+            assert_eq!(res.as_ref().unwrap_err().to_string(), "Microservice error: 503");
+        }
+    }
+
+    #[test]
+    fn waiters_outlive_the_leader() {
+        let flights = SingleFlight::default();
+        let (tx, fetch) = upstream();
+        let result = System::new("flight").block_on(future::lazy(|| {
+            //  The client of the leader went away, its request is dropped
+            drop(flights.run("k", fetch));
+            let waiter = flights.run("k", unused());
+            tx.send(7).unwrap();
+            waiter
+        }));
+        assert_eq!(result.unwrap(), 7);
+    }
+
+    #[test]
+    fn dropped_fetches_release_the_waiters() {
+        let flights = SingleFlight::<u32>::default();
+        let (tx, rx): (Waiter<u32>, _) = oneshot::channel();
+        flights.waiting.lock().unwrap().insert("k".to_owned(), vec![tx]);
+        drop(Leader { key: Some("k".to_owned()), waiting: flights.waiting.clone() });
+        assert!(rx.wait().is_err());
+        assert!(flights.waiting.lock().unwrap().is_empty());
+    }
+}
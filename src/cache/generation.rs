@@ -0,0 +1,119 @@
+//  Generations
+//  A fetch that started before an invalidation would write the old value back after the invalidation ran
+//  Every invalidation gets the next generation, a fetch only stores its value if nothing that covers its key
+//  was invalidated after it started. Invalidations are only remembered while fetches are running
+use std::sync::{Arc, Mutex};
+use super::Invalidation;
+
+#[derive(Default)]
+struct Invalidated {
+    generation: u64,
+    running: usize,
+    targets: Vec<(Invalidation, u64)>,
+}
+
+//  Shared by all workers, the invalidations of one worker concern the fetches of the others too
+#[derive(Clone, Default)]
+pub struct Generations {
+    inner: Arc<Mutex<Invalidated>>,
+}
+
+impl Generations {
+    //  Called when a fetch starts, the fetch keeps the generation until it's stored or dropped
+    pub fn start(&self) -> Generation {
+        let mut inner = self.inner.lock().unwrap();
+        inner.running += 1;
+        Generation {
+            started: inner.generation,
+            inner: self.inner.clone(),
+        }
+    }
+
+    pub fn invalidate(&self, target: &Invalidation) {
+        let mut inner = self.inner.lock().unwrap();
+        //  Nothing is running that could write an old value back
+        if inner.running == 0 {
+            return;
+        }
+        inner.generation += 1;
+        let generation = inner.generation;
+        inner.targets.push((target.clone(), generation));
+    }
+}
+
+pub struct Generation {
+    started: u64,
+    inner: Arc<Mutex<Invalidated>>,
+}
+
+impl Generation {
+    //  The key is stale if it, a prefix of it or one of its tags was invalidated after the fetch started
+    pub fn is_current(&self, key: &str, tags: &[String]) -> bool {
+        let inner = self.inner.lock().unwrap();
+        !inner.targets.iter()
+            .filter(|(_, generation)| *generation > self.started)
+            .any(|(target, _)| match target {
+                Invalidation::Key(invalidated) => invalidated == key,
+                Invalidation::Prefix(prefix) => key.starts_with(prefix.as_str()),
+                Invalidation::Tag(tag) => tags.contains(tag),
+            })
+    }
+}
+
+//  Once the last running fetch is done, no one needs the invalidations anymore
+impl Drop for Generation {
+    fn drop(&mut self) {
+        if let Ok(mut inner) = self.inner.lock() {
+            inner.running -= 1;
+            if inner.running == 0 {
+                inner.targets.clear();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tags() -> Vec<String> {
+        vec!["tag:comments".to_owned()]
+    }
+
+    #[test]
+    fn invalidations_after_the_start_are_seen() {
+        let generations = Generations::default();
+        let fetch = generations.start();
+        assert!(fetch.is_current("c:/list", &tags()));
+        generations.invalidate(&Invalidation::Key("c:/other".to_owned()));
+        generations.invalidate(&Invalidation::Prefix("c:/users".to_owned()));
+        generations.invalidate(&Invalidation::Tag("tag:users".to_owned()));
+        assert!(fetch.is_current("c:/list", &tags()));
+        for target in &[Invalidation::Key("c:/list".to_owned()), Invalidation::Prefix("c:/li".to_owned()), Invalidation::Tag("tag:comments".to_owned())] {
+            let fetch = generations.start();
+            generations.invalidate(target);
+            assert!(!fetch.is_current("c:/list", &tags()), "{:?} wasn't seen", target);
+        }
+    }
+
+    #[test]
+    fn invalidations_before_the_start_are_not() {
+        let generations = Generations::default();
+        let first = generations.start();
+        generations.invalidate(&Invalidation::Key("c:/list".to_owned()));
+        let second = generations.start();
+        assert!(!first.is_current("c:/list", &[]));
+        assert!(second.is_current("c:/list", &[]));
+    }
+
+    #[test]
+    fn invalidations_are_forgotten_when_nothing_runs() {
+        let generations = Generations::default();
+        generations.invalidate(&Invalidation::Key("c:/list".to_owned()));
+        let fetch = generations.start();
+        generations.invalidate(&Invalidation::Key("c:/list".to_owned()));
+        drop(fetch);
+        assert!(generations.inner.lock().unwrap().targets.is_empty());
+        assert!(generations.start().is_current("c:/list", &[]));
+    }
+}
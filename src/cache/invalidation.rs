@@ -0,0 +1,122 @@
+//  Invalidation
+//  Every worker keeps its own local cache, when one of them writes a value the copies of the other workers become stale
+//  The InvalidationActor resends Invalidate messages to the local caches of all workers and publishes them to Redis,
+//  so the other instances of the router drop their copies too
+use actix::prelude::*;
+use log::debug;
+use super::Invalidation;
+use super::backend::RedisNodes;
+use super::generation::Generations;
+use super::pubsub::{self, Publish, PublisherActor};
+
+//  Asks local caches to drop entries, origin is the id of the local cache that made the change and already applied it
+//  Messages that came from other instances have no origin
+#[derive(Clone)]
+pub struct Invalidate {
+    pub target: Invalidation,
+    pub origin: Option<usize>,
+}
+
+//  Invalidations are published as "<instance> <key|prefix|tag> <value>"
+fn encode(instance: &str, target: &Invalidation) -> String {
+    match target {
+        Invalidation::Key(key) => format!("{} key {}", instance, key),
+        Invalidation::Prefix(prefix) => format!("{} prefix {}", instance, prefix),
+        Invalidation::Tag(tag) => format!("{} tag {}", instance, tag),
+    }
+}
+
+fn decode(payload: &str) -> Option<(&str, Invalidation)> {
+    let mut parts = payload.splitn(3, ' ');
+    let origin = parts.next()?;
+    let kind = parts.next()?;
+    let value = parts.next()?.to_owned();
+    let target = match kind {
+        "key" => Invalidation::Key(value),
+        "prefix" => Invalidation::Prefix(value),
+        "tag" => Invalidation::Tag(value),
+        _ => return None,
+    };
+    Some((origin, target))
+}
+
+impl Message for Invalidate {
+    type Result = ();
+}
+
+pub enum InvalidationControl {
+    Subscribe(Recipient<Invalidate>),
+}
+
+impl Message for InvalidationControl {
+    type Result = ();
+}
+
+pub struct InvalidationActor {
+    listeners: Vec<Recipient<Invalidate>>,
+    publisher: Option<Addr<PublisherActor>>,
+    channel: String,
+    instance: String,
+    generations: Generations,
+}
+
+impl InvalidationActor {
+    pub fn new(publisher: Option<Addr<PublisherActor>>, channel: &str, instance: &str, generations: Generations) -> Self {
+        Self {
+            listeners: Vec::new(),
+            publisher,
+            channel: channel.to_owned(),
+            instance: instance.to_owned(),
+            generations,
+        }
+    }
+}
+
+impl Actor for InvalidationActor {
+    type Context = Context<Self>;
+}
+
+impl Handler<InvalidationControl> for InvalidationActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: InvalidationControl, _: &mut Self::Context) -> Self::Result {
+        match msg {
+            InvalidationControl::Subscribe(listener) => {
+                self.listeners.push(listener);
+            }
+        }
+    }
+}
+
+impl Handler<Invalidate> for InvalidationActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: Invalidate, _: &mut Self::Context) -> Self::Result {
+        //  Local invalidations were already seen by the running fetches, the ones of other instances weren't
+        if msg.origin.is_none() {
+            self.generations.invalidate(&msg.target);
+        }
+        for listener in &self.listeners {
+            listener.do_send(msg.clone()).ok();
+        }
+        //  Only local writes are published, messages from other instances were already seen by everyone
+        if let (Some(_), Some(publisher)) = (msg.origin, &self.publisher) {
+            publisher.do_send(Publish {
+                channel: self.channel.clone(),
+                payload: encode(&self.instance, &msg.target),
+            });
+        }
+    }
+}
+
+//  Relays the invalidations of other instances to the hub
+pub fn subscribe_invalidations(nodes: RedisNodes, channel: String, instance: String, hub: Addr<InvalidationActor>) {
+    pubsub::subscribe(nodes, channel, move |payload| {
+        if let Some((origin, target)) = decode(&payload) {
+            if origin != instance {
+                debug!("Invalidation of {:?} received from {}", target, origin);
+                hub.do_send(Invalidate { target, origin: None });
+            }
+        }
+    });
+}
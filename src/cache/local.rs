@@ -0,0 +1,253 @@
+//  Local cache
+//  A small cache of every worker that is consulted before the shared one, it saves the round-trip for hot keys
+//  Values live only for a short TTL, writes go through to the shared cache and invalidate the copies of other workers
+use actix::prelude::*;
+use failure::Error;
+use futures::{future, Future};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use super::{CacheLink, Invalidation};
+use super::generation::{Generation, Generations};
+use super::invalidation::{Invalidate, InvalidationActor, InvalidationControl};
+
+//  Gives every local cache its own id
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+struct LocalStore {
+    entries: HashMap<String, (Vec<u8>, Instant)>,
+    ttl: Duration,
+    max_entries: usize,
+}
+
+impl LocalStore {
+    fn get(&mut self, key: &str) -> Option<Vec<u8>> {
+        let expired = match self.entries.get(key) {
+            Some((_, expires)) => *expires <= Instant::now(),
+            None => return None,
+        };
+        if expired {
+            self.entries.remove(key);
+            return None;
+        }
+        self.entries.get(key).map(|(value, _)| value.clone())
+    }
+
+    fn set(&mut self, key: &str, value: Vec<u8>) {
+        if self.ttl == Duration::from_secs(0) || self.max_entries == 0 {
+            return;
+        }
+        if self.entries.len() >= self.max_entries && !self.entries.contains_key(key) {
+            let now = Instant::now();
+            self.entries.retain(|_, (_, expires)| *expires > now);
+            //  Still full, the entry that expires first goes away
+            if self.entries.len() >= self.max_entries {
+                let first = self.entries.iter()
+                    .min_by_key(|(_, (_, expires))| *expires)
+                    .map(|(key, _)| key.clone());
+                if let Some(first) = first {
+                    self.entries.remove(&first);
+                }
+            }
+        }
+        self.entries.insert(key.to_owned(), (value, Instant::now() + self.ttl));
+    }
+
+    //  The local store doesn't know the tags of its entries, so a tag drops everything, the entries are short-lived anyway
+    fn invalidate(&mut self, target: &Invalidation) {
+        match target {
+            Invalidation::Key(key) => {
+                self.entries.remove(key);
+            }
+            Invalidation::Prefix(prefix) => {
+                self.entries.retain(|key, _| !key.starts_with(prefix.as_str()));
+            }
+            Invalidation::Tag(_) => {
+                self.entries.clear();
+            }
+        }
+    }
+}
+
+//  The listener lives in the worker thread next to the store and drops keys that were written by others
+struct LocalListener {
+    id: usize,
+    store: Rc<RefCell<LocalStore>>,
+}
+
+impl Actor for LocalListener {
+    type Context = Context<Self>;
+}
+
+impl Handler<Invalidate> for LocalListener {
+    type Result = ();
+
+    fn handle(&mut self, msg: Invalidate, _: &mut Self::Context) -> Self::Result {
+        if msg.origin != Some(self.id) {
+            self.store.borrow_mut().invalidate(&msg.target);
+        }
+    }
+}
+
+//  Both levels of the cache with the same methods as CacheLink, it has to be created in the worker thread that uses it
+#[derive(Clone)]
+pub struct TieredCache {
+    id: usize,
+    store: Rc<RefCell<LocalStore>>,
+    link: CacheLink,
+    hub: Addr<InvalidationActor>,
+    generations: Generations,
+}
+
+impl TieredCache {
+    pub fn new(link: CacheLink, hub: Addr<InvalidationActor>, generations: Generations, ttl: Duration, max_entries: usize) -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let store = Rc::new(RefCell::new(LocalStore {
+            entries: HashMap::new(),
+            ttl,
+            max_entries,
+        }));
+        let listener = LocalListener { id, store: store.clone() }.start();
+        hub.do_send(InvalidationControl::Subscribe(listener.recipient()));
+        Self {
+            id,
+            store,
+            link,
+            hub,
+            generations,
+        }
+    }
+
+    //  Taken when a fetch starts, the value it fetched is only stored if the generation is still current
+    pub fn generation(&self) -> Generation {
+        self.generations.start()
+    }
+
+    //  The shared cache behind the local one
+    pub fn link(&self) -> &CacheLink {
+        &self.link
+    }
+
+    //  The local copy is used if it's fresh, otherwise the value from the shared cache is kept locally
+    pub fn get_value(&self, path: &str) -> Box<dyn Future<Item = Option<Vec<u8>>, Error = Error>> {
+        if let Some(value) = self.store.borrow_mut().get(path) {
+            return Box::new(future::ok(Some(value)));
+        }
+        let store = self.store.clone();
+        let key = path.to_owned();
+        let fut = self.link.get_value(path)
+            .map(move |opt| {
+                if let Some(value) = &opt {
+                    store.borrow_mut().set(&key, value.clone());
+                }
+                opt
+            });
+        Box::new(fut)
+    }
+
+    //  Writes both levels and, once the shared cache has the new value, tells everyone else to drop their copies
+    pub fn set_value(&self, path: &str, value: &[u8], expiration: usize, tags: &[String]) -> Box<dyn Future<Item = (), Error = Error>> {
+        self.store.borrow_mut().set(path, value.to_owned());
+        let hub = self.hub.clone();
+        let msg = Invalidate {
+            target: Invalidation::Key(path.to_owned()),
+            origin: Some(self.id),
+        };
+        let fut = self.link.set_value(path, value, expiration, tags)
+            .map(move |_| hub.do_send(msg));
+        Box::new(fut)
+    }
+
+    //  Drops the entries from both levels, the other workers and instances drop their local copies after the backend is done
+    //  Fetches that are running are told right away, before they can write an old value back
+    pub fn invalidate(&self, target: Invalidation) -> Box<dyn Future<Item = usize, Error = Error>> {
+        self.generations.invalidate(&target);
+        self.store.borrow_mut().invalidate(&target);
+        let hub = self.hub.clone();
+        let msg = Invalidate {
+            target: target.clone(),
+            origin: Some(self.id),
+        };
+        let fut = self.link.invalidate(target)
+            .map(move |removed| {
+                hub.do_send(msg);
+                removed
+            });
+        Box::new(fut)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(max_entries: usize) -> LocalStore {
+        LocalStore {
+            entries: HashMap::new(),
+            ttl: Duration::from_secs(60),
+            max_entries,
+        }
+    }
+
+    #[test]
+    fn full_store_drops_the_entry_that_expires_first() {
+        let mut store = store(2);
+        store.set("a", b"1".to_vec());
+        store.set("b", b"2".to_vec());
+        //  Overwriting a key doesn't make room
+        store.set("a", b"3".to_vec());
+        assert_eq!(store.entries.len(), 2);
+        store.entries.get_mut("b").unwrap().1 = Instant::now() + Duration::from_secs(30);
+        store.set("c", b"4".to_vec());
+        assert_eq!(store.get("b"), None);
+        assert_eq!(store.get("a"), Some(b"3".to_vec()));
+        assert_eq!(store.get("c"), Some(b"4".to_vec()));
+    }
+
+    #[test]
+    fn expired_entries_are_gone() {
+        let mut store = store(2);
+        store.set("a", b"1".to_vec());
+        store.set("b", b"2".to_vec());
+        store.entries.get_mut("a").unwrap().1 = Instant::now();
+        assert_eq!(store.get("a"), None);
+        assert!(!store.entries.contains_key("a"));
+        //  Expired entries make room before a fresh one is dropped
+        store.entries.get_mut("b").unwrap().1 = Instant::now();
+        store.set("c", b"3".to_vec());
+        store.set("d", b"4".to_vec());
+        assert_eq!(store.get("c"), Some(b"3".to_vec()));
+        assert_eq!(store.get("d"), Some(b"4".to_vec()));
+    }
+
+    #[test]
+    fn zero_ttl_or_size_disables_it() {
+        let mut disabled = store(0);
+        disabled.set("a", b"1".to_vec());
+        assert_eq!(disabled.get("a"), None);
+        let mut disabled = store(2);
+        disabled.ttl = Duration::from_secs(0);
+        disabled.set("a", b"1".to_vec());
+        assert_eq!(disabled.get("a"), None);
+    }
+
+    #[test]
+    fn invalidations_drop_keys_prefixes_and_tags() {
+        let mut store = store(10);
+        for key in &["router:1:/list", "router:1:/list?page=2", "router:1:/users"] {
+            store.set(key, b"[]".to_vec());
+        }
+        store.invalidate(&Invalidation::Key("router:1:/users".to_owned()));
+        assert_eq!(store.get("router:1:/users"), None);
+        assert!(store.get("router:1:/list").is_some());
+        store.invalidate(&Invalidation::Prefix("router:1:/list?".to_owned()));
+        assert_eq!(store.get("router:1:/list?page=2"), None);
+        assert!(store.get("router:1:/list").is_some());
+        //  The store doesn't know the tags, everything goes
+        store.set("router:1:/users", b"[]".to_vec());
+        store.invalidate(&Invalidation::Tag("comments".to_owned()));
+        assert!(store.entries.is_empty());
+    }
+}
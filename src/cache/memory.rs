@@ -0,0 +1,296 @@
+//  In-memory backend
+//  Keeps values in the process with a TTL for every entry and evicts the least recently used ones when the size limit is reached
+//  The storage is shared, the MemoryBackend is only a handle to it
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use super::backend::{ready, CacheBackend, Reply};
+
+struct Entry {
+    value: Vec<u8>,
+    expires: Instant,
+    //  Position of the entry in the recency order
+    tick: u64,
+    tags: Vec<String>,
+}
+
+struct Lru {
+    entries: HashMap<String, Entry>,
+    //  Keys ordered by the last access, the first one is evicted first
+    order: BTreeMap<u64, String>,
+    //  Keys of every tag, a removed key leaves its tags and an empty tag is dropped
+    //  Tags are counted in bytes like the entries, every key of a tag costs the key and the tag name
+    tags: HashMap<String, HashSet<String>>,
+    tick: u64,
+    bytes: usize,
+    max_bytes: usize,
+}
+
+impl Lru {
+    fn remove(&mut self, key: &str) -> bool {
+        match self.entries.remove(key) {
+            Some(entry) => {
+                self.order.remove(&entry.tick);
+                self.bytes -= key.len() + entry.value.len();
+                for tag in &entry.tags {
+                    self.untag(tag, key);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn untag(&mut self, tag: &str, key: &str) {
+        self.bytes -= key.len() + tag.len();
+        if let Some(keys) = self.tags.get_mut(tag) {
+            keys.remove(key);
+            if keys.is_empty() {
+                self.tags.remove(tag);
+                self.bytes -= tag.len();
+            }
+        }
+    }
+
+    fn touch(&mut self, key: &str) {
+        self.tick += 1;
+        let tick = self.tick;
+        if let Some(entry) = self.entries.get_mut(key) {
+            self.order.remove(&entry.tick);
+            entry.tick = tick;
+            self.order.insert(tick, key.to_owned());
+        }
+    }
+
+    //  Drops the least recently used entries until the storage fits into the limit
+    fn evict(&mut self) {
+        while self.bytes > self.max_bytes {
+            let key = match self.order.values().next() {
+                Some(key) => key.clone(),
+                None => break,
+            };
+            self.remove(&key);
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MemoryBackend(Arc<Mutex<Lru>>);
+
+impl MemoryBackend {
+    pub fn new(max_bytes: usize) -> Self {
+        let lru = Lru {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tags: HashMap::new(),
+            tick: 0,
+            bytes: 0,
+            max_bytes,
+        };
+        MemoryBackend(Arc::new(Mutex::new(lru)))
+    }
+}
+
+impl CacheBackend for MemoryBackend {
+    fn get(&mut self, key: &str) -> Reply<Option<Vec<u8>>> {
+        let mut lru = self.0.lock().unwrap();
+        let expired = match lru.entries.get(key) {
+            Some(entry) => entry.expires <= Instant::now(),
+            None => return ready(Ok(None)),
+        };
+        if expired {
+            lru.remove(key);
+            return ready(Ok(None));
+        }
+        lru.touch(key);
+        ready(Ok(lru.entries.get(key).map(|entry| entry.value.clone())))
+    }
+
+    fn set(&mut self, key: &str, value: Vec<u8>, ttl: usize) -> Reply<()> {
+        let mut lru = self.0.lock().unwrap();
+        lru.remove(key);
+        //  A value that can't fit even into an empty storage isn't cached at all
+        if key.len() + value.len() > lru.max_bytes {
+            return ready(Ok(()));
+        }
+        lru.bytes += key.len() + value.len();
+        let entry = Entry {
+            value,
+            expires: Instant::now() + Duration::from_secs(ttl as u64),
+            tick: 0,
+            tags: Vec::new(),
+        };
+        lru.entries.insert(key.to_owned(), entry);
+        lru.touch(key);
+        lru.evict();
+        ready(Ok(()))
+    }
+
+    fn ttl(&mut self, key: &str) -> Reply<Option<u64>> {
+        let lru = self.0.lock().unwrap();
+        let now = Instant::now();
+        let ttl = lru.entries.get(key)
+            .filter(|entry| entry.expires > now)
+            .map(|entry| (entry.expires - now).as_secs());
+        ready(Ok(ttl))
+    }
+
+    fn delete(&mut self, key: &str) -> Reply<usize> {
+        let mut lru = self.0.lock().unwrap();
+        ready(Ok(lru.remove(key) as usize))
+    }
+
+    fn delete_prefix(&mut self, prefix: &str) -> Reply<usize> {
+        let mut lru = self.0.lock().unwrap();
+        let keys: Vec<String> = lru.entries.keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect();
+        ready(Ok(keys.iter().filter(|key| lru.remove(key)).count()))
+    }
+
+    //  Only stored keys are tagged, a value that wasn't cached has nothing to invalidate
+    fn tag(&mut self, tag: &str, key: &str, _ttl: usize) -> Reply<()> {
+        let mut lru = self.0.lock().unwrap();
+        match lru.entries.get_mut(key) {
+            Some(entry) if !entry.tags.iter().any(|name| name == tag) => entry.tags.push(tag.to_owned()),
+            _ => return ready(Ok(())),
+        }
+        if !lru.tags.contains_key(tag) {
+            lru.bytes += tag.len();
+        }
+        lru.tags.entry(tag.to_owned()).or_default().insert(key.to_owned());
+        lru.bytes += key.len() + tag.len();
+        lru.evict();
+        ready(Ok(()))
+    }
+
+    fn delete_tag(&mut self, tag: &str) -> Reply<usize> {
+        let mut lru = self.0.lock().unwrap();
+        let keys = match lru.tags.remove(tag) {
+            Some(keys) => keys,
+            None => return ready(Ok(0)),
+        };
+        lru.bytes -= tag.len();
+        ready(Ok(keys.iter().filter(|key| lru.remove(key)).count()))
+    }
+
+    fn resize(&mut self, max_bytes: usize) {
+        let mut lru = self.0.lock().unwrap();
+        lru.max_bytes = max_bytes;
+        lru.evict();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::Future;
+    use super::*;
+
+    fn get(backend: &mut MemoryBackend, key: &str) -> Option<Vec<u8>> {
+        backend.get(key).wait().unwrap()
+    }
+
+    fn set(backend: &mut MemoryBackend, key: &str, value: &[u8], ttl: usize) {
+        backend.set(key, value.to_vec(), ttl).wait().unwrap();
+    }
+
+    fn bytes(backend: &MemoryBackend) -> usize {
+        backend.0.lock().unwrap().bytes
+    }
+
+    #[test]
+    fn expired_entries_are_gone() {
+        let mut backend = MemoryBackend::new(1024);
+        set(&mut backend, "a", b"1", 60);
+        set(&mut backend, "b", b"2", 0);
+        assert_eq!(get(&mut backend, "a"), Some(b"1".to_vec()));
+        assert_eq!(get(&mut backend, "b"), None);
+        assert!(backend.ttl("a").wait().unwrap().unwrap() > 50);
+        assert_eq!(backend.ttl("b").wait().unwrap(), None);
+        //  Reading the expired entry released its space
+        assert_eq!(bytes(&backend), 2);
+    }
+
+    #[test]
+    fn least_recently_used_is_evicted() {
+        let mut backend = MemoryBackend::new(6);
+        set(&mut backend, "a", b"1", 60);
+        set(&mut backend, "b", b"2", 60);
+        set(&mut backend, "c", b"3", 60);
+        //  Reading "a" makes "b" the oldest one
+        get(&mut backend, "a");
+        set(&mut backend, "d", b"4", 60);
+        assert_eq!(get(&mut backend, "b"), None);
+        assert!(get(&mut backend, "a").is_some());
+        assert!(get(&mut backend, "c").is_some());
+        assert!(get(&mut backend, "d").is_some());
+        assert_eq!(bytes(&backend), 6);
+    }
+
+    #[test]
+    fn overwrite_replaces_the_size() {
+        let mut backend = MemoryBackend::new(1024);
+        set(&mut backend, "a", b"12345", 60);
+        set(&mut backend, "a", b"1", 60);
+        assert_eq!(bytes(&backend), 2);
+        assert_eq!(backend.delete("a").wait().unwrap(), 1);
+        assert_eq!(bytes(&backend), 0);
+    }
+
+    #[test]
+    fn oversized_values_are_not_cached() {
+        let mut backend = MemoryBackend::new(4);
+        set(&mut backend, "a", b"1", 60);
+        set(&mut backend, "b", b"12345", 60);
+        assert_eq!(get(&mut backend, "b"), None);
+        assert!(get(&mut backend, "a").is_some());
+    }
+
+    #[test]
+    fn tags_follow_their_keys() {
+        let mut backend = MemoryBackend::new(1024);
+        set(&mut backend, "a", b"1", 60);
+        set(&mut backend, "b", b"2", 60);
+        backend.tag("t", "a", 60).wait().unwrap();
+        backend.tag("t", "b", 60).wait().unwrap();
+        //  A tag of a key that isn't stored is ignored
+        backend.tag("t", "c", 60).wait().unwrap();
+        assert_eq!(bytes(&backend), 4 + 1 + 2 * 2);
+        backend.delete("a").wait().unwrap();
+        assert_eq!(bytes(&backend), 2 + 1 + 2);
+        assert_eq!(backend.delete_tag("t").wait().unwrap(), 1);
+        assert_eq!(get(&mut backend, "b"), None);
+        assert_eq!(bytes(&backend), 0);
+        assert!(backend.0.lock().unwrap().tags.is_empty());
+    }
+
+    #[test]
+    fn evicted_keys_leave_their_tags() {
+        let mut backend = MemoryBackend::new(8);
+        set(&mut backend, "a", b"1", 60);
+        backend.tag("t", "a", 60).wait().unwrap();
+        set(&mut backend, "b", b"1234", 60);
+        set(&mut backend, "c", b"1", 60);
+        assert_eq!(get(&mut backend, "a"), None);
+        assert!(backend.0.lock().unwrap().tags.is_empty());
+        assert_eq!(bytes(&backend), 7);
+    }
+
+    #[test]
+    fn resize_evicts_down_to_the_new_limit() {
+        let mut backend = MemoryBackend::new(1024);
+        for key in &["a", "b", "c", "d"] {
+            set(&mut backend, key, b"1", 60);
+        }
+        backend.resize(4);
+        assert_eq!(bytes(&backend), 4);
+        assert_eq!(get(&mut backend, "a"), None);
+        assert_eq!(get(&mut backend, "b"), None);
+        assert!(get(&mut backend, "c").is_some());
+        assert!(get(&mut backend, "d").is_some());
+        //  Growing again keeps what is left
+        backend.resize(1024);
+        assert_eq!(bytes(&backend), 4);
+    }
+}
@@ -0,0 +1,124 @@
+//  Redis pub/sub
+//  Instances of the router tell each other about changes through Redis channels: cache invalidations and new comments
+//  Every message starts with the id of the instance that published it, so an instance can skip its own messages
+use actix::prelude::*;
+use actix_redis::RedisActor;
+use failure::Error;
+use futures::Future;
+use log::warn;
+use redis::Client;
+use std::process;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use super::backend::{check, command, request, RedisNodes};
+
+//  Identifies this process in published messages, so it can skip its own messages when they come back
+pub fn instance_id() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    format!("{}-{}", process::id(), nanos)
+}
+
+//  Publisher Actor
+//  Publishes over its own Redis connection, it's opened in the background and reopened when it breaks
+//  Any node of a cluster forwards the messages to all others, after a failed publish the next node is used
+pub struct PublisherActor {
+    nodes: RedisNodes,
+    connection: Option<(String, Addr<RedisActor>)>,
+    next: usize,
+}
+
+impl PublisherActor {
+    pub fn new(nodes: RedisNodes) -> Self {
+        Self {
+            nodes,
+            connection: None,
+            next: 0,
+        }
+    }
+
+    fn connection(&mut self) -> Option<Addr<RedisActor>> {
+        let nodes = self.nodes.get();
+        if nodes.is_empty() {
+            return None;
+        }
+        let address = &nodes[self.next % nodes.len()];
+        match &self.connection {
+            Some((current, connection)) if current == address => Some(connection.clone()),
+            _ => {
+                let connection = RedisActor::start(address.clone());
+                self.connection = Some((address.clone(), connection.clone()));
+                Some(connection)
+            }
+        }
+    }
+}
+
+impl Actor for PublisherActor {
+    type Context = Context<Self>;
+}
+
+pub struct Publish {
+    pub channel: String,
+    pub payload: String,
+}
+
+impl Message for Publish {
+    type Result = ();
+}
+
+impl Handler<Publish> for PublisherActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: Publish, context: &mut Self::Context) -> Self::Result {
+        let connection = match self.connection() {
+            Some(connection) => connection,
+            None => {
+                warn!("Can't publish to {}: no Redis node is known", msg.channel);
+                return;
+            }
+        };
+        let Publish { channel, payload } = msg;
+        let fut = request(&connection, command(vec!["PUBLISH", &channel, &payload]))
+            .and_then(check)
+            .into_actor(self)
+            .map(|_, _, _| ())
+            .map_err(move |err, act, _| {
+                warn!("Can't publish to {}: {}", channel, err);
+                act.next = act.next.wrapping_add(1);
+            });
+        context.spawn(fut);
+    }
+}
+
+//  Listens to the channel in a separate thread, because receiving from a subscription blocks
+//  Broken connections are opened again after a pause, to the next of the nodes
+pub fn subscribe<F>(nodes: RedisNodes, channel: String, receive: F)
+    where
+        F: Fn(String) + Send + 'static, {
+    thread::spawn(move || {
+        let mut next = 0;
+        loop {
+            let nodes = nodes.get();
+            if !nodes.is_empty() {
+                let addr = format!("redis://{}", nodes[next % nodes.len()]);
+                if let Err(err) = listen(&addr, &channel, &receive) {
+                    warn!("Subscription to {} failed: {}", channel, err);
+                }
+                next += 1;
+            }
+            thread::sleep(Duration::from_secs(1));
+        }
+    });
+}
+
+fn listen<F: Fn(String)>(addr: &str, channel: &str, receive: &F) -> Result<(), Error> {
+    let client = Client::open(addr)?;
+    let mut connection = client.get_connection()?;
+    let mut pubsub = connection.as_pubsub();
+    pubsub.subscribe(channel)?;
+    loop {
+        receive(pubsub.get_message()?.get_payload()?);
+    }
+}
@@ -0,0 +1,83 @@
+//  Redis Sentinel
+//  The sentinels know which server is the master of a group, the actor asks them periodically
+//  and replaces the address used by the cache connections, the publisher and the subscriber when the master fails over
+use actix::prelude::*;
+use actix_redis::{RedisActor, RespValue};
+use failure::{format_err, Error};
+use futures::Future;
+use futures::future::{self, Loop};
+use log::{info, warn};
+use std::time::Duration;
+use super::backend::{bytes, check, command, ready, request, RedisNodes, Reply};
+use crate::config::SentinelConfig;
+
+pub struct SentinelActor {
+    master: String,
+    sentinels: Vec<Addr<RedisActor>>,
+    nodes: RedisNodes,
+    interval: u64,
+}
+
+impl SentinelActor {
+    pub fn new(config: &SentinelConfig, nodes: RedisNodes) -> Self {
+        Self {
+            master: config.master.clone(),
+            sentinels: config.addresses.iter().map(|address| RedisActor::start(address.clone())).collect(),
+            nodes,
+            interval: config.interval,
+        }
+    }
+
+    //  Asks the sentinels one after another, the first one that knows the master answers
+    fn query(&self) {
+        let (sentinels, master, nodes) = (self.sentinels.clone(), self.master.clone(), self.nodes.clone());
+        let fut = future::loop_fn(0, move |idx| {
+            let sentinel = match sentinels.get(idx) {
+                Some(sentinel) => sentinel,
+                None => return ready(Err(format_err!("No sentinel knows the master {}", master))),
+            };
+            let fut = request(sentinel, command(vec!["SENTINEL", "get-master-addr-by-name", &master]))
+                .and_then(address)
+                .then(move |res| match res {
+                    Ok(Some(address)) => Ok(Loop::Break(address)),
+                    Ok(None) | Err(_) => Ok(Loop::Continue(idx + 1)),
+                });
+            Box::new(fut) as Reply<Loop<String, usize>>
+        });
+        let name = self.master.clone();
+        let fut = fut
+            .map(move |address| {
+                if nodes.get().first() != Some(&address) {
+                    info!("Redis master {} is at {}", name, address);
+                    nodes.replace(vec![address]);
+                }
+            })
+            .map_err(|err| warn!("{}", err));
+        Arbiter::spawn(fut);
+    }
+}
+
+//  The reply is [host, port], or nil when the sentinel doesn't monitor the master
+fn address(reply: RespValue) -> Result<Option<String>, Error> {
+    match check(reply)? {
+        RespValue::Nil => Ok(None),
+        RespValue::Array(parts) if parts.len() == 2 => {
+            let mut parts = parts.into_iter().map(|part| {
+                bytes(part).map(|part| String::from_utf8_lossy(&part.unwrap_or_default()).into_owned())
+            });
+            let host = parts.next().unwrap()?;
+            let port = parts.next().unwrap()?;
+            Ok(Some(format!("{}:{}", host, port)))
+        }
+        value => Err(format_err!("Unexpected sentinel reply {:?}", value)),
+    }
+}
+
+impl Actor for SentinelActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, context: &mut Self::Context) {
+        self.query();
+        context.run_interval(Duration::from_secs(self.interval), |act, _| act.query());
+    }
+}
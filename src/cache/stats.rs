@@ -0,0 +1,65 @@
+//  Cache statistics for the admin API
+//  Every request of a cached path is counted as a hit, a stale hit or a miss, and single entries can be inspected
+use serde_derive::Serialize;
+use super::entry::{now, Entry};
+
+//  How a request of a cached path was answered
+#[derive(Clone, Copy, Debug)]
+pub enum Outcome {
+    Hit,
+    //  A stale entry was served while a new one was fetched in the background
+    Stale,
+    Miss,
+}
+
+#[derive(Clone, Default, Serialize)]
+pub struct PathStats {
+    pub hits: usize,
+    pub stale: usize,
+    pub misses: usize,
+    //  Share of the requests that were answered from the cache, stale hits included
+    pub hit_ratio: f64,
+}
+
+impl PathStats {
+    pub fn record(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Hit => self.hits += 1,
+            Outcome::Stale => self.stale += 1,
+            Outcome::Miss => self.misses += 1,
+        }
+        let cached = self.hits + self.stale;
+        self.hit_ratio = cached as f64 / (cached + self.misses) as f64;
+    }
+}
+
+//  A stored entry as the backend sees it
+#[derive(Serialize)]
+pub struct KeyInfo {
+    pub key: String,
+    //  Bytes stored in the backend, with the header and after compression
+    pub size: usize,
+    //  Seconds until the backend removes the entry
+    pub ttl: Option<u64>,
+    //  The rest is read from the header, it's missing for values in another format
+    pub body_size: Option<usize>,
+    //  Seconds since the entry was stored and until it becomes stale
+    pub age: Option<u64>,
+    pub fresh_for: Option<u64>,
+    pub etag: Option<String>,
+}
+
+impl KeyInfo {
+    pub fn new(key: String, data: &[u8], ttl: Option<u64>) -> Self {
+        let entry = Entry::decode(data);
+        Self {
+            key,
+            size: data.len(),
+            ttl,
+            body_size: entry.as_ref().map(|entry| entry.body.len()),
+            age: entry.as_ref().map(|entry| now().saturating_sub(entry.meta.stored) / 1000),
+            fresh_for: entry.as_ref().map(|entry| entry.max_age()),
+            etag: entry.map(|entry| entry.meta.etag),
+        }
+    }
+}
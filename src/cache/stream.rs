@@ -0,0 +1,65 @@
+//  Redis streams
+//  An append-only log kept in Redis and trimmed to about the latest entries, entries are maps of fields
+//  The notifications keep their history in a stream, so it survives a restart of the router
+use actix_redis::RespValue;
+use failure::{format_err, Error};
+use futures::Future;
+use std::collections::HashMap;
+use super::backend::{check, command, strings, RedisBackend, Reply};
+
+pub struct RedisStream {
+    backend: RedisBackend,
+    key: String,
+    max_len: usize,
+}
+
+impl RedisStream {
+    pub fn new(backend: RedisBackend, key: &str, max_len: usize) -> Self {
+        Self {
+            backend,
+            key: key.to_owned(),
+            max_len,
+        }
+    }
+
+    //  Trimming with ~ lets Redis drop whole nodes of the stream, so it can keep a few more entries than max_len
+    pub fn append(&self, fields: &[(&str, &str)]) -> Reply<()> {
+        let max_len = self.max_len.to_string();
+        let mut args = vec!["XADD", &self.key, "MAXLEN", "~", &max_len, "*"];
+        for (name, value) in fields {
+            args.push(name);
+            args.push(value);
+        }
+        Box::new(self.backend.send(&self.key, command(args)).and_then(check).map(|_| ()))
+    }
+
+    //  The latest entries, the oldest first
+    pub fn latest(&self, count: usize) -> Reply<Vec<HashMap<String, String>>> {
+        let count = count.to_string();
+        let fut = self.backend.send(&self.key, command(vec!["XREVRANGE", &self.key, "+", "-", "COUNT", &count]))
+            .and_then(|reply| {
+                let entries = match check(reply)? {
+                    RespValue::Array(entries) => entries,
+                    value => return Err(format_err!("Unexpected Redis reply {:?}", value)),
+                };
+                let mut latest = entries.into_iter().map(fields).collect::<Result<Vec<_>, Error>>()?;
+                latest.reverse();
+                Ok(latest)
+            });
+        Box::new(fut)
+    }
+}
+
+//  An entry is [id, [name, value, name, value...]]
+fn fields(entry: RespValue) -> Result<HashMap<String, String>, Error> {
+    match entry {
+        RespValue::Array(mut parts) if parts.len() == 2 => {
+            let values = strings(parts.pop().unwrap())?;
+            Ok(values.chunks(2)
+                .filter(|pair| pair.len() == 2)
+                .map(|pair| (pair[0].clone(), pair[1].clone()))
+                .collect())
+        }
+        value => Err(format_err!("Unexpected Redis reply {:?}", value)),
+    }
+}
@@ -0,0 +1,949 @@
+//  Configuration
+//  Everything that used to be hardcoded in main (addresses of the microservices, Redis, TTLs, log level) is read from a TOML file
+//  The ConfigActor keeps the current configuration and reloads it on SIGHUP or when the file changes on disk
+use actix::prelude::*;
+use actix::actors::signal::{ProcessSignals, Signal, SignalType, Subscribe};
+use actix_web::http::Uri;
+use failure::{format_err, Error};
+use log::{error, info, warn, LevelFilter};
+use serde_derive::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use crate::{repeater, tls, upstream};
+use crate::cache::{self, Invalidation};
+
+//  The whole configuration file, every section maps to a struct below
+#[derive(Clone, Debug, Deserialize)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub log: LogConfig,
+    pub upstreams: Upstreams,
+    pub cache: CacheConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    #[serde(default)]
+    pub admin: AdminConfig,
+    #[serde(default)]
+    pub notifications: NotificationsConfig,
+}
+
+//  The listener settings are only read at startup, changing them requires a restart
+#[derive(Clone, Debug, Deserialize)]
+pub struct ServerConfig {
+    pub address: String,
+    #[serde(default = "default_workers")]
+    pub workers: usize,
+    //  How often (in seconds) the ConfigActor checks the file for changes, 0 disables the watcher and leaves only SIGHUP
+    #[serde(default)]
+    pub watch_interval: u64,
+    //  Serves HTTPS instead of plain HTTP when present
+    pub tls: Option<TlsConfig>,
+}
+
+//  Paths to PEM files, the certificate is reloaded on changes, the client verification mode needs a restart
+#[derive(Clone, Debug, Deserialize)]
+pub struct TlsConfig {
+    pub certificate: String,
+    pub private_key: String,
+    //  CA bundle used to verify client certificates of internal callers
+    pub client_ca: Option<String>,
+    #[serde(default)]
+    pub client_auth: ClientAuth,
+    //  Plain HTTP listener that redirects every request to the HTTPS one
+    pub redirect_address: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAuth {
+    #[default]
+    None,
+    Optional,
+    Required,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct LogConfig {
+    pub level: String,
+}
+
+//  The microservices we are proxying requests to
+#[derive(Clone, Debug, Deserialize)]
+pub struct Upstreams {
+    pub users: Upstream,
+    pub comments: Upstream,
+    pub comments_writer: Upstream,
+}
+
+impl Upstreams {
+    pub fn iter(&self) -> Vec<(&'static str, &Upstream)> {
+        vec![
+            ("users", &self.users),
+            ("comments", &self.comments),
+            ("comments_writer", &self.comments_writer),
+        ]
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Upstream> {
+        self.iter().into_iter().find(|(upstream, _)| *upstream == name).map(|(_, upstream)| upstream)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Upstream {
+    pub url: String,
+    //  TLS settings for https urls, the defaults of OpenSSL are used without them
+    pub tls: Option<UpstreamTls>,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct UpstreamTls {
+    //  CA bundle to trust instead of the system certificates
+    pub ca: Option<String>,
+    //  Client certificate and key presented to the microservice
+    pub certificate: Option<String>,
+    pub private_key: Option<String>,
+    //  Name used for SNI and certificate verification instead of the host of the url
+    pub server_name: Option<String>,
+    //  SHA-256 fingerprints of accepted server certificates
+    #[serde(default)]
+    pub pins: Vec<String>,
+}
+
+impl Upstream {
+    //  Joins the base url of the microservice with a path of the request
+    //  When a server name is set, it replaces the host so the connector uses it for the handshake, the base path stays
+    pub fn url(&self, path: &str) -> String {
+        let server_name = self.tls.as_ref().and_then(|tls| tls.server_name.as_ref());
+        match (server_name, self.url.parse::<Uri>()) {
+            (Some(name), Ok(uri)) => {
+                let port = uri.port_part().map(|p| p.as_u16()).unwrap_or(443);
+                format!("https://{}:{}{}{}", name, port, uri.path().trim_end_matches('/'), path)
+            }
+            _ => format!("{}{}", self.url.trim_end_matches('/'), path),
+        }
+    }
+
+    pub fn host(&self) -> Result<String, Error> {
+        let uri = self.url.parse::<Uri>()?;
+        uri.host()
+            .map(|host| host.to_owned())
+            .ok_or_else(|| format_err!("Upstream url {} has no host", self.url))
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct CacheConfig {
+    #[serde(default)]
+    pub backend: CacheKind,
+    //  Only used by the Redis backend
+    #[serde(default)]
+    pub redis: String,
+    //  TTL period in seconds
+    pub expiration: usize,
+    //  Keys start with the namespace and the schema version, so deployments sharing a Redis don't collide
+    //  and bumping the version drops every entry at once, the old ones expire on their own
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+    #[serde(default = "default_version")]
+    pub version: u32,
+    //  Bodies of at least compress_above bytes are stored compressed, bodies above max_object_bytes aren't cached
+    #[serde(default)]
+    pub compression: Compression,
+    #[serde(default = "default_compress_above")]
+    pub compress_above: usize,
+    #[serde(default = "default_max_object_bytes")]
+    pub max_object_bytes: usize,
+    //  Upstream responses above max_response_bytes fail instead of being passed through, it can't be below max_object_bytes
+    #[serde(default = "default_max_response_bytes")]
+    pub max_response_bytes: usize,
+    //  A master monitored by sentinels, or the seed nodes of a cluster, replace the redis address
+    pub sentinel: Option<SentinelConfig>,
+    pub cluster: Option<ClusterConfig>,
+    //  Number of Redis connections, commands are pipelined on each of them
+    #[serde(default = "default_connections")]
+    pub connections: usize,
+    //  Size limit of the in-memory backend, keys and values are counted
+    #[serde(default = "default_max_bytes")]
+    pub max_bytes: usize,
+    #[serde(default)]
+    pub local: LocalCacheConfig,
+    //  Policies of cached paths, paths without one are fresh for the expiration period and are never served stale
+    #[serde(default)]
+    pub paths: HashMap<String, CachePolicy>,
+    #[serde(default)]
+    pub breaker: BreakerConfig,
+    //  Entries dropped after a new comment is posted, so readers see it immediately
+    #[serde(default)]
+    pub on_new_comment: WriteInvalidation,
+    //  Paths fetched from their upstreams on an interval, so they are in the cache before anyone asks for them
+    #[serde(default)]
+    pub refresh: Vec<RefreshConfig>,
+}
+
+//  The interval in seconds has to be shorter than the ttl of the path, so the entry is replaced before it becomes stale
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct RefreshConfig {
+    pub path: String,
+    #[serde(default)]
+    pub query: String,
+    pub interval: u64,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct WriteInvalidation {
+    #[serde(default)]
+    pub keys: Vec<String>,
+    #[serde(default)]
+    pub prefixes: Vec<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+//  By default every cached variant of the list of comments is dropped with its tag
+//  A prefix would scan the whole keyspace of every Redis node, so prefixes are better left to the admin API
+impl Default for WriteInvalidation {
+    fn default() -> Self {
+        Self {
+            keys: Vec::new(),
+            prefixes: Vec::new(),
+            tags: vec![COMMENTS_TAG.to_owned()],
+        }
+    }
+}
+
+//  Tag of the list of comments, a path without a policy gets it too
+const COMMENTS_TAG: &str = "comments";
+
+impl WriteInvalidation {
+    //  Keys and prefixes are paths, they are turned into keys of the current namespace and version
+    pub fn targets(&self, cache: &CacheConfig) -> Vec<Invalidation> {
+        let keys = self.keys.iter().map(|key| Invalidation::Key(cache.key(key, "", &[])));
+        let prefixes = self.prefixes.iter().map(|prefix| Invalidation::Prefix(cache.prefix() + prefix));
+        let tags = self.tags.iter().map(|tag| Invalidation::Tag(cache.tag(tag)));
+        keys.chain(prefixes).chain(tags).collect()
+    }
+}
+
+//  An entry is fresh for ttl seconds. After that it can be served while a new value is fetched in the background
+//  for stale_while_revalidate seconds, and served when the upstream fails for stale_if_error seconds
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct CachePolicy {
+    pub ttl: usize,
+    #[serde(default)]
+    pub stale_while_revalidate: usize,
+    #[serde(default)]
+    pub stale_if_error: usize,
+    //  Tags of the entry, they allow to invalidate it together with other paths
+    #[serde(default)]
+    pub tags: Vec<String>,
+    //  Request headers that select a variant of the response, and whether every user gets their own variant
+    #[serde(default)]
+    pub vary: Vec<String>,
+    #[serde(default)]
+    pub per_user: bool,
+    //  Upstream the path is fetched from when the entry is warmed
+    #[serde(default = "default_upstream")]
+    pub upstream: String,
+}
+
+fn default_upstream() -> String {
+    "comments".to_owned()
+}
+
+impl CachePolicy {
+    //  Hard expiry in the backend of an entry that is fresh for ttl seconds
+    pub fn expiration(&self, ttl: usize) -> usize {
+        ttl + self.stale_while_revalidate.max(self.stale_if_error)
+    }
+}
+
+impl CacheConfig {
+    pub fn policy(&self, path: &str) -> CachePolicy {
+        let tags = if path == "/list" { vec![COMMENTS_TAG.to_owned()] } else { Vec::new() };
+        self.paths.get(path).cloned().unwrap_or(CachePolicy {
+            ttl: self.expiration,
+            stale_while_revalidate: 0,
+            stale_if_error: 0,
+            tags,
+            vary: Vec::new(),
+            per_user: false,
+            upstream: default_upstream(),
+        })
+    }
+
+    pub fn prefix(&self) -> String {
+        format!("{}:v{}:", self.namespace, self.version)
+    }
+
+    //  Builds the key of a response: namespace, version, path, query parameters sorted by name
+    //  and the values that select the variant, e.g. router:v1:/list?page=2#user=42
+    pub fn key(&self, path: &str, query: &str, variant: &[(String, String)]) -> String {
+        let mut key = self.prefix() + path;
+        let mut params: Vec<(String, String)> = serde_urlencoded::from_str(query).unwrap_or_default();
+        if !params.is_empty() {
+            params.sort();
+            key.push('?');
+            key += &serde_urlencoded::to_string(params).unwrap_or_default();
+        }
+        for (name, value) in variant {
+            key += &format!("#{}={}", name, value);
+        }
+        key
+    }
+
+    //  Key of requests without the vary headers and without a signed in user, the admin API selects entries by it
+    pub fn plain_key(&self, path: &str, query: &str) -> String {
+        let policy = self.policy(path);
+        let mut variant: Vec<(String, String)> = policy.vary.iter().map(|name| (name.to_lowercase(), String::new())).collect();
+        if policy.per_user {
+            variant.push(("user".to_owned(), String::new()));
+        }
+        self.key(path, query, &variant)
+    }
+
+    //  Tags don't depend on the version, so entries of an old version can still be dropped with their tag
+    pub fn tag(&self, tag: &str) -> String {
+        format!("{}:{}", self.namespace, tag)
+    }
+}
+
+fn default_namespace() -> String {
+    "router".to_owned()
+}
+
+fn default_version() -> u32 {
+    1
+}
+
+//  Per-worker cache in front of the backend, a zero TTL disables it
+//  The channel is used to tell other instances about written keys when the backend is Redis
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct LocalCacheConfig {
+    #[serde(default)]
+    pub ttl_ms: u64,
+    #[serde(default)]
+    pub max_entries: usize,
+    #[serde(default = "default_channel")]
+    pub channel: String,
+}
+
+impl Default for LocalCacheConfig {
+    fn default() -> Self {
+        Self {
+            ttl_ms: 0,
+            max_entries: 0,
+            channel: default_channel(),
+        }
+    }
+}
+
+fn default_channel() -> String {
+    "router:invalidate".to_owned()
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheKind {
+    #[default]
+    Redis,
+    Memory,
+}
+
+//  Addresses are host:port, the sentinels are asked for the master every interval seconds
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct SentinelConfig {
+    pub master: String,
+    pub addresses: Vec<String>,
+    #[serde(default = "default_sentinel_interval")]
+    pub interval: u64,
+}
+
+fn default_sentinel_interval() -> u64 {
+    2
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ClusterConfig {
+    pub seeds: Vec<String>,
+}
+
+impl CacheConfig {
+    //  Addresses of the Redis nodes the cache starts with, the master of a sentinel group is found later
+    pub fn redis_nodes(&self) -> Result<Vec<String>, Error> {
+        match (&self.sentinel, &self.cluster) {
+            (Some(_), Some(_)) => Err(format_err!("Cache can't use a sentinel and a cluster at once")),
+            (Some(_), None) => Ok(Vec::new()),
+            (None, Some(cluster)) => Ok(cluster.seeds.clone()),
+            (None, None) => Ok(vec![cache::redis_address(&self.redis)?]),
+        }
+    }
+}
+
+//  After failures errors in a row the backend isn't used for cooldown seconds, 0 failures disables the breaker
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct BreakerConfig {
+    #[serde(default = "default_failures")]
+    pub failures: u32,
+    #[serde(default = "default_cooldown")]
+    pub cooldown: u64,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            failures: default_failures(),
+            cooldown: default_cooldown(),
+        }
+    }
+}
+
+fn default_failures() -> u32 {
+    5
+}
+
+fn default_cooldown() -> u64 {
+    10
+}
+
+fn default_connections() -> usize {
+    4
+}
+
+fn default_max_bytes() -> usize {
+    64 * 1024 * 1024
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+fn default_compress_above() -> usize {
+    1024
+}
+
+fn default_max_object_bytes() -> usize {
+    1024 * 1024
+}
+
+fn default_max_response_bytes() -> usize {
+    8 * 1024 * 1024
+}
+
+//  Maximum number of requests a worker accepts per second, 0 means no limit
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct LimitsConfig {
+    #[serde(default)]
+    pub requests_per_second: u32,
+}
+
+//  New comments are sent to the WebSocket clients of all instances, through this Redis channel when the cache uses Redis
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct NotificationsConfig {
+    #[serde(default = "default_notifications_channel")]
+    pub channel: String,
+    //  How many of the latest updates are kept for history requests and for clients that resume after a reconnect
+    #[serde(default = "default_notifications_history")]
+    pub history: usize,
+    //  Redis stream that keeps the history of this instance across restarts, only used with the Redis cache backend
+    pub stream: Option<String>,
+    //  How many comments can wait for a slow client, and what happens to the next one when they fill up
+    //  A resumed client gets up to the whole history at once, so the queue has to hold it
+    #[serde(default = "default_notifications_queue")]
+    pub queue: usize,
+    #[serde(default)]
+    pub overflow: Overflow,
+    //  Overflow policies of topic patterns like "alerts/*", the longest matching pattern wins
+    #[serde(default)]
+    pub topics: HashMap<String, Overflow>,
+    //  How long (in seconds) a long polling request waits for new comments
+    #[serde(default = "default_poll_timeout")]
+    pub poll_timeout: u64,
+    //  Signs the WebSocket tokens of clients that can't send the identity cookie, tokens aren't issued without it
+    pub token_secret: Option<String>,
+    //  Signs the identity cookie of signed in users, all instances need the same key
+    //  Without it a random key is used, so users have to sign in again after a restart and on every other instance
+    pub cookie_key: Option<String>,
+    //  Origins of the pages that can open WebSocket connections besides pages of this host, like "https://example.com"
+    #[serde(default)]
+    pub origins: Vec<String>,
+    //  How long (in seconds) a token can be used to open a connection
+    #[serde(default = "default_token_ttl")]
+    pub token_ttl: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Overflow {
+    #[default]
+    DropOldest,
+    DropNewest,
+    Disconnect,
+}
+
+impl NotificationsConfig {
+    pub fn overflow(&self, topic: &str) -> Overflow {
+        self.topics.iter()
+            .filter(|(pattern, _)| repeater::matches(pattern, topic))
+            .max_by_key(|(pattern, _)| pattern.len())
+            .map(|(_, overflow)| *overflow)
+            .unwrap_or(self.overflow)
+    }
+}
+
+impl Default for NotificationsConfig {
+    fn default() -> Self {
+        Self {
+            channel: default_notifications_channel(),
+            history: default_notifications_history(),
+            stream: None,
+            queue: default_notifications_queue(),
+            overflow: Overflow::default(),
+            topics: HashMap::new(),
+            poll_timeout: default_poll_timeout(),
+            token_secret: None,
+            cookie_key: None,
+            origins: Vec::new(),
+            token_ttl: default_token_ttl(),
+        }
+    }
+}
+
+fn default_notifications_channel() -> String {
+    "router:comments".to_owned()
+}
+
+fn default_notifications_history() -> usize {
+    100
+}
+
+fn default_notifications_queue() -> usize {
+    128
+}
+
+fn default_poll_timeout() -> u64 {
+    30
+}
+
+fn default_token_ttl() -> u64 {
+    60
+}
+
+//  The admin API is disabled without a token, requests have to send it as a bearer token
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct AdminConfig {
+    pub token: Option<String>,
+}
+
+fn default_workers() -> usize {
+    1
+}
+
+impl Config {
+    //  Reads and parses the file, then checks the values, so a broken file never replaces a working configuration
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format_err!("Can't read {}: {}", path.display(), e))?;
+        let config: Config = toml::from_str(&content)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        self.server.address.parse::<SocketAddr>()
+            .map_err(|e| format_err!("Invalid server address {}: {}", self.server.address, e))?;
+        if self.server.workers == 0 {
+            return Err(format_err!("At least one worker is required"));
+        }
+        self.log_level()?;
+        if let Some(tls) = &self.server.tls {
+            if tls.client_auth != ClientAuth::None && tls.client_ca.is_none() {
+                return Err(format_err!("Client verification requires client_ca"));
+            }
+            if let Some(address) = &tls.redirect_address {
+                address.parse::<SocketAddr>()
+                    .map_err(|e| format_err!("Invalid redirect address {}: {}", address, e))?;
+            }
+            //  Loading the files checks that they exist and that the key matches the certificate
+            tls::context(tls)?;
+        }
+        for (_, upstream) in self.upstreams.iter() {
+            let uri = upstream.url.parse::<Uri>()
+                .map_err(|e| format_err!("Invalid upstream url {}: {}", upstream.url, e))?;
+            upstream.host()?;
+            match (uri.scheme_part().map(|s| s.as_str()), &upstream.tls) {
+                (Some("https"), Some(tls)) => {
+                    if tls.certificate.is_some() != tls.private_key.is_some() {
+                        return Err(format_err!("Upstream {} needs both certificate and private_key", upstream.url));
+                    }
+                    upstream::connector(tls)?;
+                }
+                (Some("http"), Some(_)) => return Err(format_err!("Upstream url {} has TLS settings but isn't https", upstream.url)),
+                (Some("http"), None) | (Some("https"), None) => { }
+                _ => return Err(format_err!("Upstream url {} must be http or https", upstream.url)),
+            }
+        }
+        if self.cache.backend == CacheKind::Redis {
+            let single = self.cache.sentinel.is_none() && self.cache.cluster.is_none();
+            if single && !self.cache.redis.starts_with("redis://") {
+                return Err(format_err!("Invalid redis address {}", self.cache.redis));
+            }
+            self.cache.redis_nodes()?;
+            let sentinels = self.cache.sentinel.iter().flat_map(|sentinel| &sentinel.addresses);
+            let seeds = self.cache.cluster.iter().flat_map(|cluster| &cluster.seeds);
+            for address in sentinels.chain(seeds) {
+                match address.rsplit_once(':').map(|(_, port)| port.parse::<u16>()) {
+                    Some(Ok(_)) => { }
+                    _ => return Err(format_err!("Redis node address {} must be host:port", address)),
+                }
+            }
+            if let Some(sentinel) = &self.cache.sentinel {
+                if sentinel.addresses.is_empty() || sentinel.interval == 0 {
+                    return Err(format_err!("Sentinel needs addresses and an interval greater than zero"));
+                }
+            }
+            if self.cache.cluster.as_ref().map(|cluster| cluster.seeds.is_empty()).unwrap_or(false) {
+                return Err(format_err!("Cluster needs at least one seed"));
+            }
+        }
+        if self.cache.connections == 0 {
+            return Err(format_err!("Cache connections must be greater than zero"));
+        }
+        if self.cache.expiration == 0 {
+            return Err(format_err!("Cache expiration must be greater than zero"));
+        }
+        if self.cache.max_object_bytes == 0 {
+            return Err(format_err!("Cache max_object_bytes must be greater than zero"));
+        }
+        if self.cache.max_response_bytes < self.cache.max_object_bytes {
+            return Err(format_err!("Cache max_response_bytes of {} is below max_object_bytes of {}",
+                self.cache.max_response_bytes, self.cache.max_object_bytes));
+        }
+        if self.cache.namespace.is_empty() || self.cache.namespace.contains(char::is_whitespace) {
+            return Err(format_err!("Invalid cache namespace {:?}", self.cache.namespace));
+        }
+        for (path, policy) in &self.cache.paths {
+            if policy.ttl == 0 {
+                return Err(format_err!("Cache ttl of {} must be greater than zero", path));
+            }
+            if self.upstreams.get(&policy.upstream).is_none() {
+                return Err(format_err!("Unknown upstream {} of {}", policy.upstream, path));
+            }
+        }
+        for refresh in &self.cache.refresh {
+            if !refresh.path.starts_with('/') {
+                return Err(format_err!("Refresh path {} has to start with /", refresh.path));
+            }
+            let ttl = self.cache.policy(&refresh.path).ttl as u64;
+            if refresh.interval == 0 || refresh.interval >= ttl {
+                return Err(format_err!("Refresh interval of {} must be between 1 and its ttl of {}s", refresh.path, ttl));
+            }
+        }
+        if self.notifications.channel.is_empty() {
+            return Err(format_err!("Notifications channel can't be empty"));
+        }
+        if self.notifications.history == 0 {
+            return Err(format_err!("Notifications history must keep at least one update"));
+        }
+        if self.notifications.stream.as_ref().map(|stream| stream.is_empty()).unwrap_or(false) {
+            return Err(format_err!("Notifications stream can't be empty"));
+        }
+        if self.notifications.queue < self.notifications.history {
+            return Err(format_err!("Notifications queue of {} can't hold the history of {} a resumed client receives",
+                self.notifications.queue, self.notifications.history));
+        }
+        if self.notifications.poll_timeout == 0 {
+            return Err(format_err!("Notifications poll timeout must be at least 1s"));
+        }
+        if self.notifications.token_secret.as_ref().map(|secret| secret.len() < 16).unwrap_or(false) {
+            return Err(format_err!("Notifications token secret must have at least 16 characters"));
+        }
+        if self.notifications.cookie_key.as_ref().map(|key| key.len() < 32).unwrap_or(false) {
+            return Err(format_err!("Notifications cookie key must have at least 32 characters"));
+        }
+        for origin in &self.notifications.origins {
+            let valid = origin.split("://").nth(1).map(|host| !host.is_empty() && !host.contains('/')).unwrap_or(false);
+            if !valid {
+                return Err(format_err!("Origin {} must be a scheme and a host without a path", origin));
+            }
+        }
+        if self.notifications.token_ttl == 0 {
+            return Err(format_err!("Notifications token ttl must be at least 1s"));
+        }
+        if let Some(pattern) = self.notifications.topics.keys().find(|pattern| !repeater::valid_pattern(pattern)) {
+            return Err(format_err!("Invalid topic pattern {} of notifications", pattern));
+        }
+        if self.admin.token.as_ref().map(|token| token.is_empty()).unwrap_or(false) {
+            return Err(format_err!("Admin token can't be empty"));
+        }
+        Ok(())
+    }
+
+    pub fn log_level(&self) -> Result<LevelFilter, Error> {
+        self.log.level.parse()
+            .map_err(|_| format_err!("Invalid log level {}", self.log.level))
+    }
+}
+
+//  The configuration is shared between the workers, every State holds a clone of this handle
+//  and reads the current version for each request
+#[derive(Clone)]
+pub struct SharedConfig(Arc<RwLock<Arc<Config>>>);
+
+impl SharedConfig {
+    pub fn new(config: Config) -> Self {
+        SharedConfig(Arc::new(RwLock::new(Arc::new(config))))
+    }
+
+    pub fn get(&self) -> Arc<Config> {
+        self.0.read().unwrap().clone()
+    }
+
+    fn replace(&self, config: Arc<Config>) {
+        *self.0.write().unwrap() = config;
+    }
+}
+
+//  Messages
+//  ConfigUpdate is sent to every subscribed actor after a successful reload
+#[derive(Clone)]
+pub struct ConfigUpdate(pub Arc<Config>);
+
+impl Message for ConfigUpdate {
+    type Result = ();
+}
+
+//  Reload asks the actor to read the file again, it is sent for SIGHUP and by the file watcher
+pub struct Reload;
+
+impl Message for Reload {
+    type Result = ();
+}
+
+//  Config Actor
+//  Holds the path to the file, the shared configuration and the actors that have to be notified about changes
+pub struct ConfigActor {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    shared: SharedConfig,
+    listeners: Vec<Recipient<ConfigUpdate>>,
+}
+
+impl ConfigActor {
+    pub fn new(path: PathBuf, shared: SharedConfig, listeners: Vec<Recipient<ConfigUpdate>>) -> Self {
+        let modified = modified(&path);
+        Self {
+            path,
+            modified,
+            shared,
+            listeners,
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+impl Actor for ConfigActor {
+    type Context = Context<Self>;
+
+    //  We subscribe to the process signals to get SIGHUP and start the file watcher if it's enabled
+    fn started(&mut self, context: &mut Self::Context) {
+        let signals = System::current().registry().get::<ProcessSignals>();
+        signals.do_send(Subscribe(context.address().recipient()));
+
+        let interval = self.shared.get().server.watch_interval;
+        if interval > 0 {
+            context.run_interval(Duration::from_secs(interval), |act, context| {
+                let modified = modified(&act.path);
+                if modified != act.modified {
+                    act.modified = modified;
+                    context.notify(Reload);
+                }
+            });
+        }
+    }
+}
+
+impl Handler<Signal> for ConfigActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: Signal, context: &mut Self::Context) -> Self::Result {
+        if let SignalType::Hup = msg.0 {
+            info!("SIGHUP received, reloading {}", self.path.display());
+            context.notify(Reload);
+        }
+    }
+}
+
+impl Handler<Reload> for ConfigActor {
+    type Result = ();
+
+    //  An invalid file is rejected and the old configuration stays active
+    fn handle(&mut self, _: Reload, _: &mut Self::Context) -> Self::Result {
+        let mut config = match Config::load(&self.path) {
+            Ok(config) => config,
+            Err(err) => {
+                error!("Configuration rejected, keeping the old one: {}", err);
+                return;
+            }
+        };
+        let old = self.shared.get();
+        if old.server.address != config.server.address || old.server.workers != config.server.workers {
+            warn!("Server address and workers can't be changed without a restart");
+            config.server.address = old.server.address.clone();
+            config.server.workers = old.server.workers;
+        }
+        let mode = |config: &Config| config.server.tls.as_ref()
+            .map(|tls| (tls.client_auth, tls.redirect_address.clone()));
+        if mode(&old) != mode(&config) {
+            warn!("TLS can't be enabled, disabled or change client verification without a restart");
+            //  The listeners keep running with the old settings, so the shared config has to describe them
+            match (&old.server.tls, &mut config.server.tls) {
+                (Some(old), Some(tls)) => {
+                    tls.client_auth = old.client_auth;
+                    tls.redirect_address = old.redirect_address.clone();
+                }
+                (old, tls) => *tls = old.clone(),
+            }
+        }
+        if old.cache.backend != config.cache.backend || old.cache.redis != config.cache.redis || old.cache.local != config.cache.local
+            || old.cache.connections != config.cache.connections
+            || old.cache.sentinel != config.cache.sentinel || old.cache.cluster != config.cache.cluster {
+            warn!("Cache backend, Redis nodes, connections and local cache can't be changed without a restart");
+            config.cache.backend = old.cache.backend;
+            config.cache.redis = old.cache.redis.clone();
+            config.cache.local = old.cache.local.clone();
+            config.cache.connections = old.cache.connections;
+            config.cache.sentinel = old.cache.sentinel.clone();
+            config.cache.cluster = old.cache.cluster.clone();
+        }
+        if old.notifications.channel != config.notifications.channel || old.notifications.history != config.notifications.history
+            || old.notifications.stream != config.notifications.stream || old.notifications.cookie_key != config.notifications.cookie_key {
+            warn!("Notifications channel, history, stream and cookie key can't be changed without a restart");
+            config.notifications.channel = old.notifications.channel.clone();
+            config.notifications.history = old.notifications.history;
+            config.notifications.stream = old.notifications.stream.clone();
+            config.notifications.cookie_key = old.notifications.cookie_key.clone();
+        }
+        //  The kept values have to fit the new ones too, a smaller queue can't hold the history that stays
+        if let Err(err) = config.validate() {
+            error!("Configuration rejected with the settings that need a restart, keeping the old one: {}", err);
+            return;
+        }
+        if old.cache.namespace != config.cache.namespace || old.cache.version != config.cache.version {
+            info!("Cache keys moved to {}, entries of {} are no longer used", config.cache.prefix(), old.cache.prefix());
+        }
+        if let Ok(level) = config.log_level() {
+            log::set_max_level(level);
+        }
+        let config = Arc::new(config);
+        self.shared.replace(config.clone());
+        for listener in &self.listeners {
+            listener.do_send(ConfigUpdate(config.clone())).ok();
+        }
+        info!("Configuration reloaded");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: &str = "[server]\naddress = \"127.0.0.1:8080\"\n[log]\nlevel = \"info\"\n\
+        [upstreams.users]\nurl = \"http://127.0.0.1:8001\"\n[upstreams.comments]\nurl = \"http://127.0.0.1:8003\"\n\
+        [upstreams.comments_writer]\nurl = \"http://127.0.0.1:8004\"\n[cache]\nbackend = \"memory\"\nexpiration = 10\n";
+
+    fn cache(extra: &str) -> CacheConfig {
+        toml::from_str(&format!("expiration = 10\n{}", extra)).unwrap()
+    }
+
+    #[test]
+    fn query_parameters_are_sorted() {
+        let cache = cache("");
+        assert_eq!(cache.key("/list", "", &[]), "router:v1:/list");
+        assert_eq!(cache.key("/list", "b=2&a=1", &[]), "router:v1:/list?a=1&b=2");
+        assert_eq!(cache.key("/list", "b=2&a=1", &[]), cache.key("/list", "a=1&b=2", &[]));
+        //  Repeated parameters keep every value
+        assert_eq!(cache.key("/list", "a=2&a=1", &[]), "router:v1:/list?a=1&a=2");
+    }
+
+    #[test]
+    fn query_encoding_is_normalised() {
+        let cache = cache("");
+        assert_eq!(cache.key("/list", "q=a%20b", &[]), cache.key("/list", "q=a+b", &[]));
+        assert_eq!(cache.key("/list", "q=%7Euser", &[]), "router:v1:/list?q=%7Euser");
+    }
+
+    #[test]
+    fn namespace_version_and_variant_are_part_of_the_key() {
+        let cache = cache("namespace = \"blog\"\nversion = 3");
+        let variant = vec![("accept-language".to_owned(), "de".to_owned()), ("user".to_owned(), "42".to_owned())];
+        assert_eq!(cache.key("/list", "page=2", &variant), "blog:v3:/list?page=2#accept-language=de#user=42");
+        assert_eq!(cache.tag("comments"), "blog:comments");
+    }
+
+    #[test]
+    fn queue_has_to_hold_the_history() {
+        let config = |notifications: &str| -> Result<(), Error> {
+            let config: Config = toml::from_str(&format!("{}[notifications]\n{}", BASE, notifications))?;
+            config.validate()
+        };
+        assert!(config("").is_ok());
+        assert!(config("history = 10\nqueue = 10").is_ok());
+        assert!(config("history = 10\nqueue = 9").is_err());
+        assert!(config("history = 0").is_err());
+    }
+
+    #[test]
+    fn refresh_paths_are_absolute() {
+        let config = |refresh: &str| -> Result<(), Error> {
+            let config: Config = toml::from_str(&format!("{}[cache.paths.\"/list\"]\nttl = 60\n[[cache.refresh]]\n{}", BASE, refresh))?;
+            config.validate()
+        };
+        assert!(config("path = \"/list\"\ninterval = 30").is_ok());
+        assert!(config("path = \"@evil.host/list\"\ninterval = 5").is_err());
+        assert!(config("path = \"list\"\ninterval = 5").is_err());
+    }
+
+    #[test]
+    fn plain_key_has_empty_variants() {
+        let cache = cache("[paths.\"/list\"]\nttl = 10\nvary = [\"Accept-Language\"]\nper_user = true");
+        assert_eq!(cache.plain_key("/list", ""), "router:v1:/list#accept-language=#user=");
+        assert_eq!(cache.plain_key("/other", ""), "router:v1:/other");
+    }
+
+    fn upstream(url: &str, server_name: Option<&str>) -> Upstream {
+        let tls = server_name.map(|name| UpstreamTls {
+            ca: None,
+            certificate: None,
+            private_key: None,
+            server_name: Some(name.to_owned()),
+            pins: Vec::new(),
+        });
+        Upstream { url: url.to_owned(), tls }
+    }
+
+    #[test]
+    fn upstream_urls_keep_the_base_path() {
+        assert_eq!(upstream("http://127.0.0.1:8003", None).url("/list"), "http://127.0.0.1:8003/list");
+        assert_eq!(upstream("http://127.0.0.1:8003/", None).url("/list"), "http://127.0.0.1:8003/list");
+        assert_eq!(upstream("https://api.example.com/v1/", None).url("/list"), "https://api.example.com/v1/list");
+        //  The server name replaces only the host
+        let pinned = upstream("https://10.0.0.5/v1", Some("comments.internal"));
+        assert_eq!(pinned.url("/list"), "https://comments.internal:443/v1/list");
+        assert_eq!(upstream("https://10.0.0.5:8443", Some("comments.internal")).url("/list"), "https://comments.internal:8443/list");
+        assert_eq!(upstream("https://10.0.0.5:8443/", Some("comments.internal")).url("/list?page=2"), "https://comments.internal:8443/list?page=2");
+        assert_eq!(pinned.host().unwrap(), "10.0.0.5");
+    }
+}
@@ -0,0 +1,139 @@
+//  Server-Sent Events 
+//  Some clients sit behind proxies that break WebSockets, they can read the same comments from /events as a plain HTTP stream
+//  The EventsActor subscribes to the RepeaterActor like the NotificationActor does, and writes every comment as an event
+//  whose id is its cursor (<epoch>:<seq>), so a browser that reconnects sends Last-Event-ID and receives the comments it missed 
+use actix::{Actor, ActorContext, Addr, AsyncContext, Handler};
+use actix_web::dev::Drain;
+use actix_web::HttpContext;
+use log::warn;
+use serde::Serialize;
+use serde_derive::Serialize;
+use std::sync::Arc;
+use std::time::Duration;
+use super::State;
+use crate::config::Config;
+use crate::protocol::Item;
+use crate::repeater::{Connection, Cursor, Listener, Outbox, RepeaterActor, RepeaterControl, RepeaterUpdate};
+
+//  Proxies close connections that stay silent, a comment line keeps the stream alive 
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(20);
+//  How long a browser waits before it reconnects 
+const RETRY_MS: u64 = 3000;
+
+pub struct EventsActor { 
+    repeater: Addr<RepeaterActor>,
+    topics: Vec<String>,
+    since: Option<Cursor>,
+    outbox: Outbox,
+}
+
+//  Sent after the missed comments, like the resumed frame of the WebSocket protocol 
+#[derive(Serialize)]
+struct Resumed { 
+    replayed: usize,
+    complete: bool,
+}
+
+impl EventsActor { 
+    pub fn new(repeater: Addr<RepeaterActor>, topics: Vec<String>, since: Option<Cursor>) -> Self { 
+        Self { 
+            repeater,
+            topics,
+            since,
+            outbox: Outbox::default(),
+        }
+    }
+
+    //  An event is a block of field lines ended by an empty line, JSON data never spans several lines 
+    fn event<T: Serialize>(&self, id: Option<String>, name: &str, data: &T, context: &mut HttpContext<Self, State>) { 
+        let data = match serde_json::to_string(data) { 
+            Ok(data) => data,
+            Err(_) => return,
+        };
+        let id = id.map(|id| format!("id: {}\n", id)).unwrap_or_default();
+        context.write(format!("{}event: {}\ndata: {}\n\n", id, name, data));
+    }
+
+    //  The missed comments that still wait in the queue are written before the summary event. Without the replay the stream
+    //  isn't subscribed, so it ends and the browser reconnects with the id of the last comment it received 
+    fn replay(&mut self, since: Cursor, context: &mut HttpContext<Self, State>) { 
+        self.resume(since, context, |act, res, context| match res { 
+            Ok(replayed) => act.event(None, "resumed", &Resumed { replayed: replayed.count, complete: replayed.complete }, context),
+            Err(err) => { 
+                warn!("Can't resume an event stream: {}", err);
+                context.stop();
+            }
+        });
+    }
+}
+
+//  Comments are queued in the Outbox and written as events 
+impl Connection for EventsActor { 
+    fn outbox(&mut self) -> &mut Outbox { 
+        &mut self.outbox
+    }
+
+    fn repeater(&self) -> &Addr<RepeaterActor> { 
+        &self.repeater
+    }
+
+    fn topics(&self) -> &[String] { 
+        &self.topics
+    }
+
+    fn config(context: &Self::Context) -> Arc<Config> { 
+        context.state().config.get()
+    }
+
+    fn write(&mut self, updates: Vec<RepeaterUpdate>, context: &mut Self::Context) { 
+        for update in updates { 
+            let item = Item::from(update);
+            self.event(Some(format!("{}:{}", item.epoch, item.seq)), "comment", &item, context);
+        }
+    }
+
+    fn drain(context: &mut Self::Context) -> Drain<Self> { 
+        context.drain()
+    }
+
+    //  Ending the stream makes the browser reconnect with the id of the last comment it received 
+    fn disconnect(&mut self, context: &mut Self::Context) { 
+        warn!("Closing an event stream with {} pending comments", self.outbox.len());
+        context.stop();
+    }
+}
+
+impl Actor for EventsActor { 
+    type Context = HttpContext<Self, State>;
+
+    fn started(&mut self, context: &mut Self::Context) { 
+        context.write(format!("retry: {}\n\n", RETRY_MS));
+        //  A reconnecting browser subscribes with the replay, so no comment arrives before the missed ones 
+        match self.since.take() { 
+            Some(since) => self.replay(since, context),
+            None => for topic in &self.topics { 
+                let msg = RepeaterControl::Subscribe { 
+                    topic: topic.clone(),
+                    listener: Listener::new(context.address()),
+                };
+                self.repeater.do_send(msg);
+            },
+        }
+        context.run_interval(KEEPALIVE_INTERVAL, |_, context| { 
+            context.write(": keepalive\n\n");
+        });
+    }
+
+    //  The HttpContext stops the actor when a write to the closed connection fails, the keepalives make sure it happens 
+    fn stopped(&mut self, context: &mut Self::Context) { 
+        self.repeater.do_send(RepeaterControl::Leave(Listener::new(context.address())));
+    }
+}
+
+impl Handler<RepeaterUpdate> for EventsActor { 
+    type Result = ();
+
+    fn handle(&mut self, msg: RepeaterUpdate, context: &mut Self::Context) -> Self::Result { 
+        self.enqueue(msg, context);
+    }
+}
@@ -1,25 +1,48 @@
 use actix_web::{
-    client, middleware, server, fs, ws, App, Error, Form, HttpMessage,
-    HttpRequest, HttpResponse, FutureResponse, Result,
+    client, error, middleware, server, fs, ws, App, Error, Form, HttpContext, HttpMessage,
+    HttpRequest, HttpResponse, FutureResponse, Query, Result,
 };
-use actix::{Actor, Addr, SyncArbiter};
+use actix::{Actor, Addr, Arbiter};
+use actix_web::client::ClientConnector;
+use actix_web::dev::HttpResponseBuilder;
 use actix_web::http::{self, header, StatusCode};
+use actix_web::http::header::HttpDate;
 use actix_web::middleware::{Finished, Middleware, Response, Started};
 use actix_web::middleware::identity::RequestIdentity;
 use actix_web::middleware::identity::{CookieIdentityPolicy, IdentityService};
 use failure::format_err;
 use futures::{IntoFuture, Future, future};
-use log::{error, debug};
+use log::{error, debug, warn, LevelFilter};
 use serde::{Deserialize, Serialize};
 use serde_derive::{Deserialize, Serialize};
 use std::cell::RefCell;
+use std::env;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 mod cache;
-use crate::cache::{CacheActor, CacheLink};
+use crate::cache::{Breaker, CacheActor, CacheLink, Directives, Entry, Generations, Invalidation, InvalidationActor, Outcome, PublisherActor, RedisNodes, RedisStream, SentinelActor, SingleFlight, TieredCache};
+mod config;
+use crate::config::{CacheConfig, CacheKind, CachePolicy, Config, ConfigActor, NotificationsConfig, SharedConfig};
+mod tls;
+use crate::tls::{Certificates, TlsActor};
+mod upstream;
+use crate::upstream::{UpstreamActor, UpstreamClients};
 mod repeater;
-use crate::repeater::{RepeaterActor, RepeaterUpdate};
+use crate::repeater::{Cursor, GetDeliveryStats, Poll, PollError, Polled, RepeaterActor, RepeaterUpdate, ALL_TOPICS, DEFAULT_TOPIC};
 mod notification;
+mod bridge;
+use crate::bridge::BridgeActor;
+mod protocol;
+use crate::protocol::Item;
 use crate::notification::{NotificationActor};
+mod events;
+use crate::events::EventsActor;
+mod refresher;
+mod token;
+use crate::refresher::CacheRefresherActor;
 
 
 fn boxed<I, E, F>(fut: F) -> Box<dyn Future<Item = I, Error = E>> 
@@ -35,40 +58,66 @@ fn boxed<I, E, F>(fut: F) -> Box<dyn Future<Item = I, Error = E>>
 //  To send requests to other microservices, we need an HTTP client. The actix_web crate contains one
 //  WE need ot add two functions, Get and Post Requests
 
+//  Both functions take the ClientConnector of the upstream, which carries its TLS settings 
+
+//  The body of an upstream response together with its Cache-Control directives and content type 
+pub struct Fetched { 
+    body: Vec<u8>,
+    directives: Directives,
+    content_type: Option<String>,
+}
+
 // **GET request 
-fn get_request(url: &str) -> impl Future<Item = Vec<u8>, Error = Error> { 
+//  limit is the largest body we accept, MessageBody stops at 256 KiB otherwise 
+fn get_request(connector: Addr<ClientConnector>, url: &str, limit: usize) -> impl Future<Item = Fetched, Error = Error> { 
     //  ClientRequest has shortcuts that create builders with a preset HTTP method
     //  We call the get method that only sets the Method::GET value to a request that in implemented as the calling method of the ClientRequestBuilder 
     client::ClientRequest::get(url) 
-        
+        .with_connector(connector)
         .finish().into_future()
         //  We use finish, because GET request don't comtain a body value 
         //  All these methods return a Result with a ClientRequest instance as a successful value 
         
-        .and_then(|req| { 
+        .and_then(move |req| { 
         //  Since we have a Future value, we can use the and_tehn method to add the next processing step  
                 req.send()
                 //  The send method createas a SendRequest instance which implements the Future trait and sends a request to a server 
                 
                     .map_err(Error::from)
                     
-                    .and_then(|resp| resp.body().from_err())
-                    //  If a request has sent we can take a MessageBody value with the body method call 
-                    //  This method is a part of the HttpMessage trait
-                    //  MessageBody also implements a Future trait with a Bytes value and we use the and_then method to extend a chain of futures
-                    //  and transform a value frim SendRequest to Bytes 
-
-                    .map(|bytes| bytes.to_vec())
-                    //  we use to_vec() method of Bytes to convert it into Vec<u8> and provide this value as a response to a client
+                    .and_then(move |resp| { 
+                        //  An error page must not be cached and served as a successful response, the error lets the cache use a stale entry instead 
+                        if !resp.status().is_success() { 
+                            error!("Microservice error: {}", resp.status());
+                            return boxed(future::err(format_err!("Microservice error: {}", resp.status()).into()));
+                        }
+                        //  The upstream decides whether and how long its response can be cached 
+                        let directives = resp.headers().get(header::CACHE_CONTROL)
+                            .and_then(|value| value.to_str().ok())
+                            .map(Directives::parse)
+                            .unwrap_or_default();
+                        let content_type = resp.headers().get(header::CONTENT_TYPE)
+                            .and_then(|value| value.to_str().ok())
+                            .map(str::to_owned);
+                        //  If a request has sent we can take a MessageBody value with the body method call 
+                        //  This method is a part of the HttpMessage trait
+                        //  MessageBody also implements a Future trait with a Bytes value and we use the and_then method to extend a chain of futures
+                        //  and transform a value frim SendRequest to Bytes 
+                        let fut = resp.body().limit(limit).from_err()
+                            //  we use to_vec() method of Bytes to convert it into Vec<u8> and provide this value as a response to a client
+                            .map(move |bytes| Fetched { body: bytes.to_vec(), directives, content_type });
+                        boxed(fut)
+                    })
         })
 }
 // **POST request 
-fn post_request<T, O>(url: &str, params: T) -> impl Future<Item = O, Error = Error> 
+fn post_request<T, O>(connector: Addr<ClientConnector>, url: &str, params: T) -> impl Future<Item = O, Error = Error> 
     where  
         T: Serialize,
          O: for <'de> Deserialize<'de> + 'static,  { 
     
     client::ClientRequest::post(url)
+            .with_connector(connector)
     //  The post_request function creates ClientRequestBuilder with teh ost method of ClientRequest adn dfills a form with values from the paras variable 
             .form(params).into_future().and_then(|req| { 
                 //  We convert Result into Futur and send a request to a server 
@@ -105,9 +154,12 @@ pub struct UserId {
 #[derive(Deserialize)]
 pub struct AddComment  { 
     pub text: String,
+    //  Thread or article the comment belongs to, clients subscribed to it are notified 
+    #[serde(default)]
+    pub topic: Option<String>,
 }
 
-#[derive(Serialize, Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct NewComment  { 
     pub uid: String,
     pub text: String,
@@ -120,9 +172,10 @@ pub struct NewComment  {
 //  signup route 
 //  The Router microservice uses the /signup route to resent a signup request to a users microservuce bound to the 127.0.0.1:8001 address 
 //  This request creates new users with filled from UserForm, passed with a parameter wrapped with the Form Type 
-fn signup(params: Form<UserForm>) -> FutureResponse<HttpResponse> {
+fn signup((req, params): (HttpRequest<State>, Form<UserForm>)) -> FutureResponse<HttpResponse> {
     //  We call the post_request function that we declared before tot send a POST request to a users microservice 
-    let fut = post_request("http://127.0.0.1:8001/signup", params.into_inner())
+    let url = req.state().config.get().upstreams.users.url("/signup");
+    let fut = post_request(req.state().clients.get("users"), &url, params.into_inner())
         .map(|_: ()| { 
             //  If successful, we return a response witha  302 status code 
             HttpResponse::Found()
@@ -142,7 +195,8 @@ fn signin((req, params): (HttpRequest<State>, Form<UserForm>)) -> FutureResponse
 //  Form: we need to extract the UserForm struct from the request body 
 
 //  We can use the post_request , but expect it to return a UserId value in its response 
-    let fut = post_request("http://127.0.0.1:8001/signin", params.into_inner())
+    let url = req.state().config.get().upstreams.users.url("/signin");
+    let fut = post_request(req.state().clients.get("users"), &url, params.into_inner())
         .map(move |id: UserId| { 
             //  we can use the Remember Method since HTtpRequest implements the REquest Identity trait and we plugged in IdentityService to app
             req.remember(id.id);
@@ -162,8 +216,18 @@ fn signin((req, params): (HttpRequest<State>, Form<UserForm>)) -> FutureResponse
 fn new_comment((req, params): (HttpRequest<State>, Form<AddComment>)) -> FutureResponse<HttpResponse> { 
 
     let repeater = req.state().repeater.clone();
+    let url = req.state().config.get().upstreams.comments_writer.url("/new_comment");
+    let connector = req.state().clients.get("comments_writer");
+    let cache = req.state().cache.clone();
+    let config = req.state().config.get();
+    let targets = config.cache.on_new_comment.targets(&config.cache);
 
     //  First, we call the identity method of the RequestIdentity trait found in HttpRequest -> this will return the user's ID
+    let AddComment { text, topic } = params.into_inner();
+    let topic = topic.unwrap_or_else(|| DEFAULT_TOPIC.to_owned());
+    if !repeater::valid_topic(&topic) { 
+        return Box::new(future::err(error::ErrorBadRequest("Invalid topic")));
+    }
     let fut = req.identity()
         .ok_or(format_err!("You have sign-in first!").into())
         .into_future()
@@ -172,18 +236,38 @@ fn new_comment((req, params): (HttpRequest<State>, Form<AddComment>)) -> FutureR
             //  We then use the return User ID value to prepare a request for the comments microservice 
             let new_comment = NewComment { 
                 uid,
-                text: params.into_inner().text,
+                text,
                 //  we then extract the text field from an AddComment form and create a NewComment Struct with teh user's ID and a comment 
             };
 
             //  The new comment handler is called when a user adds a new commetn and add an extra step to send a NewComment value to a repeater
-            let update = RepeaterUpdate(new_comment.clone());
+            //  Only the clients subscribed to the topic of the comment are notified 
+            let update = RepeaterUpdate { 
+                seq: 0,
+                epoch: String::new(),
+                topic,
+                comment: new_comment.clone(),
+                origin: None,
+            };
                 repeater    
                     .send(update)
                     .then(move |_| Ok(new_comment))
         })
         .and_then(move |params| { 
-            post_request::<_, ()>("http://127.0.0.1:8004/new_comment", params)
+            post_request::<_, ()>(connector, &url, params)
+        })
+        //  Once the comment is stored, the cached lists are dropped, so the next read fetches a list with the new comment 
+        .and_then(move |_| { 
+            let invalidations = targets.into_iter().map(move |target| { 
+                cache.invalidate(target.clone()).then(move |res| { 
+                    match res { 
+                        Ok(removed) => debug!("Invalidated {:?}: {} entries", target, removed),
+                        Err(err) => error!("Can't invalidate {:?}: {}", target, err),
+                    }
+                    Ok::<_, Error>(())
+                })
+            });
+            future::join_all(invalidations)
         })
         .then(move |_| { 
             let res = HttpResponse::build_from(&req)
@@ -199,19 +283,80 @@ fn new_comment((req, params): (HttpRequest<State>, Form<AddComment>)) -> FutureR
 //  To view all comments that were created by the previous handler, we have to send a GET request to the comments microservice 
 //  with the get_request function that we created before and resend the response data to a client: 
 
+//  Cache-Control and Vary of a cached response 
+fn caching_headers<'a>(resp: &'a mut HttpResponseBuilder, policy: &CachePolicy, entry: &Entry) -> &'a mut HttpResponseBuilder { 
+    if entry.no_store { 
+        resp.header(header::CACHE_CONTROL, "no-store");
+    } else { 
+        //  Responses of a single user must not be stored by shared caches 
+        let scope = if policy.per_user { "private" } else { "public" };
+        let mut cache_control = format!("{}, max-age={}", scope, entry.max_age());
+        if policy.stale_while_revalidate > 0 { 
+            cache_control += &format!(", stale-while-revalidate={}", policy.stale_while_revalidate);
+        }
+        if policy.stale_if_error > 0 { 
+            cache_control += &format!(", stale-if-error={}", policy.stale_if_error);
+        }
+        resp.header(header::CACHE_CONTROL, cache_control);
+    }
+    if !policy.vary.is_empty() { 
+        resp.header(header::VARY, policy.vary.join(", "));
+    }
+    resp
+}
+
 //  Caching the list of comments...
 fn comments(req: HttpRequest<State>) -> FutureResponse<HttpResponse> { 
     
     //  Create a Future to get a value from another microservice using the get_request method that we have implemented before 
-    let fut = get_request("http://127.0.0.1:8003/list");
+    //  Query parameters are passed to the upstream, every combination of them is cached separately 
+    let mut url = req.state().config.get().upstreams.comments.url("/list");
+    if !req.query_string().is_empty() { 
+        url = format!("{}?{}", url, req.query_string());
+    }
+    let limit = req.state().config.get().cache.max_response_bytes;
+    let fut = get_request(req.state().clients.get("comments"), &url, limit);
     //  Get a reference to state, and call the cache method by passing the /list path, then create a Future instance to obtain a new value 
-    let fut = req.state().cache("/list", fut)
-        .map(|data| { 
-            HttpResponse::Ok().body(data)
+    let policy = req.state().config.get().cache.policy("/list");
+    let fut = req.state().cache(&req, "/list", fut)
+        .map(move |entry| { 
+            //  Clients that already have this version of the list get an empty 304 response, with the same caching headers
+            //  as the full one so caches in between keep them 
+            if not_modified(req.headers(), &entry) { 
+                return caching_headers(&mut HttpResponse::NotModified(), &policy, &entry)
+                    .header(header::ETAG, entry.meta.etag.as_str())
+                    .finish();
+            }
+            let mut resp = HttpResponse::Ok();
+            caching_headers(&mut resp, &policy, &entry);
+            //  The content type of the upstream response is replayed for cached responses 
+            if let Some(content_type) = &entry.meta.content_type { 
+                resp.content_type(content_type.as_str());
+            }
+            resp.header(header::ETAG, entry.meta.etag.as_str())
+                .header(header::LAST_MODIFIED, HttpDate::from(entry.last_modified()))
+                .body(entry.body)
         });
     Box::new(fut)
 }
 
+//  Conditional GET 
+//  If-None-Match is checked first, If-Modified-Since is only used when the client sent no entity tags 
+fn not_modified(headers: &http::HeaderMap, entry: &Entry) -> bool { 
+    if let Some(value) = headers.get(header::IF_NONE_MATCH) { 
+        let value = value.to_str().unwrap_or("");
+        //  Weak comparison, a W/ prefix doesn't matter for GET requests 
+        return value.split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .any(|tag| tag == "*" || tag == entry.meta.etag);
+    }
+    headers.get(header::IF_MODIFIED_SINCE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<HttpDate>().ok())
+        .map(|since| entry.last_modified() <= SystemTime::from(since))
+        .unwrap_or(false)
+}
+
 //  Counter
 //  The handler that prints the total quantity of requests also has quite simple implementation
 fn counter(req: HttpRequest<State>) -> String { 
@@ -224,13 +369,350 @@ fn counter(req: HttpRequest<State>) -> String {
         //  Now we will add some middleware that will count every reqest to the microservice 
 }
 
+//  Coalescing 
+//  Shows how many upstream fetches were made for cache misses and how many requests waited for another one's fetch 
+fn coalescing(req: HttpRequest<State>) -> HttpResponse { 
+    HttpResponse::Ok().json(req.state().flights.stats())
+}
+
+//  Notifications 
+//  Shows how many comments slow WebSocket clients missed and how many of them were disconnected 
+fn notification_stats(req: HttpRequest<State>) -> FutureResponse<HttpResponse> { 
+    let fut = req.state().repeater.send(GetDeliveryStats)
+        .from_err()
+        .map(|stats| HttpResponse::Ok().json(stats));
+    Box::new(fut)
+}
+
+//  Cache backend 
+//  Shows the errors of the cache backend and whether the circuit breaker currently skips it 
+fn cache_stats(req: HttpRequest<State>) -> FutureResponse<HttpResponse> { 
+    let fut = req.state().cache.link().stats()
+        .from_err()
+        .map(|stats| HttpResponse::Ok().json(stats));
+    Box::new(fut)
+}
+
+//  Cache administration 
+//  The admin handlers are served under /admin and only answer requests with the admin token, see AdminAuth 
+
+//  An entry is selected by its full key, or by a path and a query that are turned into the key of the current namespace 
+//  Entries of other variants can only be selected by their key 
+#[derive(Deserialize)]
+pub struct KeyParams { 
+    key: Option<String>,
+    path: Option<String>,
+    #[serde(default)]
+    query: String,
+}
+
+impl KeyParams { 
+    fn key(&self, config: &Config) -> Result<String, Error> { 
+        match (&self.key, &self.path) { 
+            (Some(key), _) => Ok(key.clone()),
+            (None, Some(path)) => Ok(config.cache.plain_key(path, &self.query)),
+            (None, None) => Err(error::ErrorBadRequest("key or path is required")),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct Purged { 
+    removed: usize,
+}
+
+#[derive(Serialize)]
+pub struct Warmed { 
+    key: String,
+    stored: bool,
+    size: usize,
+    etag: String,
+}
+
+//  Shows the size, the remaining TTL and the age of an entry 
+fn inspect_key((req, params): (HttpRequest<State>, Query<KeyParams>)) -> FutureResponse<HttpResponse> { 
+    let key = match params.key(&req.state().config.get()) { 
+        Ok(key) => key,
+        Err(err) => return Box::new(future::err(err)),
+    };
+    let fut = req.state().cache.link().inspect(&key)
+        .from_err()
+        .map(|info| match info { 
+            Some(info) => HttpResponse::Ok().json(info),
+            None => HttpResponse::NotFound().finish(),
+        });
+    Box::new(fut)
+}
+
+//  Removes an entry from the backend and from the local caches of all workers 
+fn purge_key((req, params): (HttpRequest<State>, Query<KeyParams>)) -> FutureResponse<HttpResponse> { 
+    let key = match params.key(&req.state().config.get()) { 
+        Ok(key) => key,
+        Err(err) => return Box::new(future::err(err)),
+    };
+    purge(&req, Invalidation::Key(key))
+}
+
+//  Removes every entry whose path starts with the given one, the query isn't used 
+fn purge_prefix((req, params): (HttpRequest<State>, Query<KeyParams>)) -> FutureResponse<HttpResponse> { 
+    let prefix = match &params.path { 
+        Some(path) => req.state().config.get().cache.prefix() + path,
+        None => return Box::new(future::err(error::ErrorBadRequest("path is required"))),
+    };
+    purge(&req, Invalidation::Prefix(prefix))
+}
+
+fn purge(req: &HttpRequest<State>, target: Invalidation) -> FutureResponse<HttpResponse> { 
+    let fut = req.state().cache.invalidate(target)
+        .map_err(error::ErrorServiceUnavailable)
+        .map(|removed| HttpResponse::Ok().json(Purged { removed }));
+    Box::new(fut)
+}
+
+//  Fetches a path from its upstream and stores the response 
+//  The path is appended to the url of the upstream, without the leading / it could name another host: @evil.host/x 
+fn warm_key((req, params): (HttpRequest<State>, Query<KeyParams>)) -> FutureResponse<HttpResponse> { 
+    let path = match &params.path { 
+        Some(path) if path.starts_with('/') => path.clone(),
+        Some(_) => return Box::new(future::err(error::ErrorBadRequest("path has to start with /"))),
+        None => return Box::new(future::err(error::ErrorBadRequest("path is required"))),
+    };
+    let fut = req.state().warm(&path, &params.query)
+        .map(|(key, entry)| { 
+            HttpResponse::Ok().json(Warmed { 
+                key,
+                stored: !entry.no_store,
+                size: entry.body.len(),
+                etag: entry.meta.etag,
+            })
+        });
+    Box::new(fut)
+}
+
+//  Hits, stale hits, misses and the hit ratio of every cached path since the start 
+fn path_stats(req: HttpRequest<State>) -> FutureResponse<HttpResponse> { 
+    let fut = req.state().cache.link().path_stats()
+        .from_err()
+        .map(|stats| HttpResponse::Ok().json(stats));
+    Box::new(fut)
+}
+
+//  Topics of a WebSocket connection or an event stream, separated by commas: /ws?topics=article/42,news/*&since=42 
+#[derive(Deserialize)]
+pub struct TopicParams { 
+    topics: Option<String>,
+    //  Sequence number and epoch of the last comment a reconnecting client saw 
+    since: Option<u64>,
+    epoch: Option<String>,
+}
+
+impl TopicParams { 
+    fn parse(req: &HttpRequest<State>) -> Result<Self, Error> { 
+        serde_urlencoded::from_str(req.query_string()).map_err(error::ErrorBadRequest)
+    }
+
+    //  Clients that don't choose topics receive every comment 
+    fn topics(&self) -> Result<Vec<String>, Error> { 
+        let topics: Vec<String> = self.topics.as_ref()
+            .map(|topics| topics.split(',').map(str::to_owned).collect())
+            .unwrap_or_else(|| vec![ALL_TOPICS.to_owned()]);
+        if !topics.iter().all(|topic| repeater::valid_pattern(topic)) { 
+            return Err(error::ErrorBadRequest("Invalid topic"));
+        }
+        Ok(topics)
+    }
+
+    fn cursor(&self) -> Option<Cursor> { 
+        self.since.map(|seq| Cursor { epoch: self.epoch.clone(), seq })
+    }
+}
+
+//  Browsers can't set the Authorization header of a WebSocket handshake, they offer the token as a subprotocol instead:
+//  new WebSocket(url, ["bearer", token]). Tokens are never read from the query, it ends up in access logs 
+const TOKEN_PROTOCOL: &str = "bearer";
+
+fn protocol_token<S>(req: &HttpRequest<S>) -> Option<&str> { 
+    let mut protocols = req.headers().get_all(header::SEC_WEBSOCKET_PROTOCOL).iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim);
+    protocols.by_ref().find(|protocol| *protocol == TOKEN_PROTOCOL)?;
+    protocols.next()
+}
+
+//  The signed in user of a WebSocket handshake, from the identity cookie or from a token in the Authorization header or
+//  the subprotocols 
+fn ws_user<S>(req: &HttpRequest<S>, config: &NotificationsConfig) -> Option<String> { 
+    if let Some(user) = req.identity() { 
+        return Some(user);
+    }
+    let secret = config.token_secret.as_ref()?;
+    let token = req.headers().get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or_else(|| protocol_token(req))?;
+    token::verify(secret, token)
+}
+
+//  Browsers send the identity cookie with the handshake of any page, so a page of another site could open a connection
+//  in the name of the user. The Origin of the page has to be this host or one of the configured origins, clients that
+//  aren't browsers send no Origin 
+fn allowed_origin<S>(req: &HttpRequest<S>, config: &NotificationsConfig) -> bool { 
+    let origin = match req.headers().get(header::ORIGIN) { 
+        Some(origin) => origin.to_str().unwrap_or(""),
+        None => return true,
+    };
+    config.origins.iter().any(|allowed| allowed == origin)
+        || origin.split("://").nth(1) == Some(req.connection_info().host())
+}
+
 //  Add a handler for HTTP request with a resource method call of App and passed the ws_connect function to its
+//  Anonymous clients and pages of other sites aren't upgraded 
 fn ws_connect(req: &HttpRequest<State>) -> Result<HttpResponse, Error>  {
-    let repeater = req.state().repeater.clone().recipient();
-    //  Clone address of RepeaterActor, converting it into a Recipient which is then used for creating a NotificationActor instance
+    let config = req.state().config.get();
+    if !allowed_origin(req, &config.notifications) { 
+        return Ok(HttpResponse::Forbidden().finish());
+    }
+    let params = TopicParams::parse(req)?;
+    let topics = params.topics()?;
+    let user = match ws_user(req, &config.notifications) { 
+        Some(user) => user,
+        None => return Ok(HttpResponse::Unauthorized().header(header::WWW_AUTHENTICATE, "Bearer").finish()),
+    };
+    let repeater = req.state().repeater.clone();
+    //  Clone address of RepeaterActor, the NotificationActor instance uses it to change its subscriptions and to ask for the history
+
+    //  To start that actor instance, the handshake response gets a WebsocketContext for this actor as its body, like ws::start does
+    //  A client that offered the token subprotocol has to see it accepted, browsers close the connection otherwise
+    let mut resp = ws::handshake(req)?;
+    if protocol_token(req).is_some() { 
+        resp.header(header::SEC_WEBSOCKET_PROTOCOL, TOKEN_PROTOCOL);
+    }
+    let stream = ws::WsStream::new(req.payload());
+    let actor = NotificationActor::new(repeater, user, topics, params.cursor());
+    Ok(resp.body(ws::WebsocketContext::create(req.clone(), actor, stream)))
+}
+
+//  WebSocket token 
+//  A signed in user gets a short-lived token for clients that can't send the identity cookie with the WebSocket handshake 
+#[derive(Serialize)]
+pub struct WsToken { 
+    token: String,
+    expires_in: u64,
+}
+
+fn ws_token(req: HttpRequest<State>) -> Result<HttpResponse, Error> { 
+    let config = req.state().config.get();
+    let secret = match config.notifications.token_secret { 
+        Some(ref secret) => secret,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    let user = req.identity().ok_or_else(|| error::ErrorUnauthorized("You have to sign in first"))?;
+    let expires_in = config.notifications.token_ttl;
+    let token = token::issue(secret, &user, expires_in).map_err(error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok()
+        .header(header::CACHE_CONTROL, "no-store")
+        .json(WsToken { token, expires_in }))
+}
+
+//  Direct messages 
+//  A signed in user sends a message to the WebSocket connections of another user, on every instance 
+//  Messages aren't stored, a user that isn't connected doesn't receive them 
+#[derive(Deserialize)]
+pub struct DirectMessage { 
+    to: String,
+    text: String,
+}
+
+fn send_message((req, params): (HttpRequest<State>, Form<DirectMessage>)) -> Result<HttpResponse, Error> { 
+    let uid = req.identity().ok_or_else(|| error::ErrorUnauthorized("You have to sign in first"))?;
+    let DirectMessage { to, text } = params.into_inner();
+    if to.is_empty() || to.contains(char::is_whitespace) { 
+        return Err(error::ErrorBadRequest("Invalid user"));
+    }
+    let update = RepeaterUpdate { 
+        seq: 0,
+        epoch: String::new(),
+        topic: repeater::user_topic(&to),
+        comment: NewComment { uid, text },
+        origin: None,
+    };
+    req.state().repeater.do_send(update);
+    Ok(HttpResponse::Accepted().finish())
+}
+
+//  Long polling 
+//  Clients without WebSocket or SSE poll with the cursor of the last batch: /api/comments/poll?topics=article/*&since=42&epoch=e1
+//  The request waits until a matching comment is posted or the poll timeout expires, an empty batch means nothing new 
+//  Complete is false when comments after the cursor were lost, the client should load the list again 
+#[derive(Serialize)]
+pub struct CommentBatch { 
+    epoch: String,
+    seq: u64,
+    complete: bool,
+    comments: Vec<Item>,
+}
+
+fn poll_comments(req: HttpRequest<State>) -> FutureResponse<HttpResponse> { 
+    let (since, patterns) = match TopicParams::parse(&req).and_then(|params| Ok((params.cursor(), params.topics()?))) { 
+        Ok(params) => params,
+        Err(err) => return Box::new(future::err(err)),
+    };
+    let timeout = Duration::from_secs(req.state().config.get().notifications.poll_timeout);
+    let fut = req.state().repeater.send(Poll { since, patterns, timeout })
+        .from_err()
+        .and_then(|res| res.map_err(|err| match err { 
+            PollError::UnknownCursor => error::ErrorBadRequest("Unknown cursor, poll again with the epoch or without since"),
+            PollError::Canceled => error::ErrorServiceUnavailable("Notifications aren't available"),
+        }))
+        .map(|polled| { 
+            let Polled { epoch, seq, updates, complete } = polled;
+            let comments = updates.into_iter().map(Item::from).collect();
+            HttpResponse::Ok()
+                .header(header::CACHE_CONTROL, "no-store")
+                .json(CommentBatch { epoch, seq, complete, comments })
+        });
+    Box::new(fut)
+}
+
+//  Event stream 
+//  The same comments as Server-Sent Events, a browser that reconnects sends the id of the last event it received in Last-Event-ID 
+fn events(req: &HttpRequest<State>) -> Result<HttpResponse, Error> { 
+    let params = TopicParams::parse(req)?;
+    let topics = params.topics()?;
+    let since = match req.headers().get("Last-Event-ID") { 
+        Some(id) => Some(id.to_str().ok().and_then(|id| Cursor::parse(id.trim())).ok_or_else(|| error::ErrorBadRequest("Invalid Last-Event-ID"))?),
+        None => params.cursor(),
+    };
+    let actor = EventsActor::new(req.state().repeater.clone(), topics, since);
+    //  Compression and proxy buffering would hold the events back 
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .content_encoding(http::ContentEncoding::Identity)
+        .header(header::CACHE_CONTROL, "no-cache")
+        .header("X-Accel-Buffering", "no")
+        .body(HttpContext::create(req.clone(), actor)))
+}
 
-    //  To start that actor instance, you have to use the ws::start method that uses the current Request and bootstraps WebsocketContext for this actor
-    ws::start(req, NotificationActor::new(repeater))
+//  HTTP to HTTPS redirect 
+//  The plain HTTP listener answers every request with a permanent redirect to the same path on the HTTPS port 
+fn https_redirect(req: &HttpRequest, port: u16) -> HttpResponse { 
+    let info = req.connection_info();
+    let host = info.host();
+    //  Strip the port of the plain listener, but keep IPv6 addresses in brackets intact 
+    let host = match host.rfind(':') { 
+        Some(idx) if !host[idx..].contains(']') => &host[..idx],
+        _ => host,
+    };
+    let path = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+    let location = if port == 443 { 
+        format!("https://{}{}", host, path)
+    } else { 
+        format!("https://{}:{}{}", host, port, path)
+    };
+    HttpResponse::MovedPermanently()
+        .header(header::LOCATION, location)
+        .finish()
 }
 
 ////////////////////////////////////////////////////////////////
@@ -245,54 +727,191 @@ fn ws_connect(req: &HttpRequest<State>) -> Result<HttpResponse, Error>  {
 //   Adding Websocketsupport to a server 
 pub struct State { 
     counter: RefCell<i64>,
-    cache: CacheLink,
+    //  Start of the current rate limiting window and the number of requests accepted in it
+    window: RefCell<(Instant, u32)>,
+    cache: TieredCache,
     repeater: Addr<RepeaterActor>,
+    config: SharedConfig,
+    clients: UpstreamClients,
+    flights: SingleFlight<Entry>,
 }
 //  WE need to create a new constructor because we have to provide a CacheLink Instance with the actual address of the caching actor:
 impl State { 
-    fn new(cache: CacheLink, repeater: Addr<RepeaterActor>) -> Self  {
+    fn new(cache: TieredCache, repeater: Addr<RepeaterActor>, config: SharedConfig, clients: UpstreamClients, flights: SingleFlight<Entry>) -> Self  {
         Self {
             counter: RefCell::default(),
+            window: RefCell::new((Instant::now(), 0)),
             cache,
-            repeater
+            repeater,
+            config,
+            clients,
+            flights,
         }
     }
     //  To simply our of caching, we will add the cache method to our State implementation
     //  This method will wrap any provided future with a path and try to extract the cached value 
-    fn cache<F>(&self, path: &str, fut: F) -> impl Future<Item = Vec<u8>, Error = Error> 
+    fn cache<F>(&self, req: &HttpRequest<State>, path: &str, fut: F) -> impl Future<Item = Entry, Error = Error> 
         where 
-            F: Future<Item = Vec<u8>, Error = Error> + 'static, { 
+            F: Future<Item = Fetched, Error = Error> + 'static, { 
                 
                 //  We have to use a cloned linked because have to move it to the closure that uses it to store a new valie 
                 let link = self.cache.clone();
+                let flights = self.flights.clone();
+                //  The policy of the path tells how long an entry is fresh and how long it can be served stale 
+                let config = self.config.get();
+                let policy = config.cache.policy(path);
+
+                //  The key is built from the path, the query and the values the response varies on 
+                let mut variant: Vec<(String, String)> = policy.vary.iter()
+                    .map(|name| { 
+                        let value = req.headers().get(name.as_str())
+                            .and_then(|value| value.to_str().ok())
+                            .unwrap_or("");
+                        (name.to_lowercase(), value.to_owned())
+                    })
+                    .collect();
+                if policy.per_user { 
+                    variant.push(("user".to_owned(), req.identity().unwrap_or_default()));
+                }
+                let key = config.cache.key(path, req.query_string(), &variant);
+                //  Hits and misses are counted per path, not per key 
+                let (stats, name) = (link.link().clone(), path.to_owned());
 
-                let path = path.to_owned();
+                //  Obtains a new value and stores it with its soft and hard expiry, the closure is only called when the value is needed 
+                //  Concurrent fetches of the same path share one request, only the first of them runs the provided future 
+                //  The previous entry keeps the modification time when the upstream returns the same body 
+                let fetch = { 
+                    let (link, key, policy) = (link.clone(), key.clone(), policy.clone());
+                    move |previous: Option<Entry>| { 
+                        let res = store(link, config, key.clone(), policy, fut, previous);
+                        //  This method wraps the provided Future vaue with another Future trait implementation 
+                        flights.run(&key, res)
+                    }
+                };
 
                 //  Extracting the cached value and get a Future that requests a vlaue from the cache
-                link.get_value(&path)
-                    
-                    .from_err::<Error>()
+                //  The cache must never fail a request, if it can't answer the value is fetched from the upstream 
+                link.get_value(&key)
+                    .then(|res| res.or_else(|err| { 
+                        error!("Cache read failed, treated as a miss: {}", err);
+                        Ok::<_, Error>(None)
+                    }))
                     //  SInce the method returns an Option, we can use the and_then method to check that the value exists in a cache and return the vlaue to the client
                     .and_then(move |opt| { 
-                        if let Some(cached) = opt { 
-                            debug!("Cached value used");
-                            boxed(future::ok(cached))
-                        //  If the value isn't availabe, it will obtain a new one, and afterwards, it receives the store-copied value to cache, and returns 
-                        //  the value to the client 
-                        } else { 
-                            let res = fut.and_then(move |data|  {
-                                link.set_value(&path, &data)
-                                    .then(move |_|  {
-                                        debug!("Cached Updated");
-                                        future::ok::<_, Error>(data)
-                                    }).from_err::<Error>()
-                                        
-                            });
-                            //  This method wraps the provided Future vaue with another Future trait implementation 
-                            boxed(res)
+                        match opt.and_then(|data| Entry::decode(&data)) { 
+                            Some(entry) if entry.is_fresh() => { 
+                                debug!("Cached value used");
+                                stats.record(&name, Outcome::Hit);
+                                boxed(future::ok(entry))
+                            }
+                            //  A stale value is returned right away, and the new one is fetched in the background 
+                            Some(entry) if entry.stale_for() < policy.stale_while_revalidate as u64 => { 
+                                debug!("Stale value used, revalidating {}", key);
+                                stats.record(&name, Outcome::Stale);
+                                Arbiter::spawn(fetch(Some(entry.clone())).map(|_| ()).map_err(|err| error!("Revalidation failed: {}", err)));
+                                boxed(future::ok(entry))
+                            }
+                            //  The client waits for a new value, but gets the stale one if the upstream fails 
+                            Some(entry) if entry.stale_for() < policy.stale_if_error as u64 => { 
+                                stats.record(&name, Outcome::Miss);
+                                let res = fetch(Some(entry.clone())).or_else(move |err| { 
+                                    error!("Upstream failed, stale value used: {}", err);
+                                    Ok(entry)
+                                });
+                                boxed(res)
+                            }
+                            //  If the value isn't availabe, it will obtain a new one, and afterwards, it receives the store-copied value to cache, and returns 
+                            //  the value to the client 
+                            entry => { 
+                                stats.record(&name, Outcome::Miss);
+                                fetch(entry)
+                            }
                         }
                     })
             }
+
+    fn warm(&self, path: &str, query: &str) -> impl Future<Item = (String, Entry), Error = Error> { 
+        warm(self.cache.clone(), self.flights.clone(), self.config.get(), &self.clients, path, query)
+    }
+}
+
+//  Warming 
+//  Fetches a path from its upstream and stores it whatever is in the cache, so the next request doesn't wait for the upstream 
+//  The warmed entry is the one of requests without the vary headers and without a signed in user 
+//  The admin API and the CacheRefresherActor both use it, so the other workers drop their local copies of the written key 
+fn warm(link: TieredCache, flights: SingleFlight<Entry>, config: Arc<Config>, clients: &UpstreamClients, path: &str, query: &str) -> impl Future<Item = (String, Entry), Error = Error> { 
+    let policy = config.cache.policy(path);
+    let key = config.cache.plain_key(path, query);
+    let fut = get_request(clients.get(&policy.upstream), &upstream_url(&config, &policy, path, query), config.cache.max_response_bytes);
+    //  The current entry keeps its modification time if the body didn't change 
+    link.get_value(&key)
+        .then(|res| Ok::<_, Error>(res.ok().and_then(|opt| opt).and_then(|data| Entry::decode(&data))))
+        .and_then(move |previous| { 
+            let res = store(link, config, key.clone(), policy, fut, previous);
+            flights.run(&key, res).map(move |entry| (key, entry))
+        })
+}
+
+//  Url of a cached path on the upstream it's fetched from when warmed, the upstream is checked when the configuration is loaded 
+fn upstream_url(config: &Config, policy: &CachePolicy, path: &str, query: &str) -> String { 
+    let url = config.upstreams.get(&policy.upstream)
+        .map(|upstream| upstream.url(path))
+        .unwrap_or_default();
+    if query.is_empty() { url } else { format!("{}?{}", url, query) }
+}
+
+//  Builds an entry from the upstream response and writes it to the cache with its soft and hard expiry 
+fn store<F>(link: TieredCache, config: Arc<Config>, key: String, policy: CachePolicy, fut: F, previous: Option<Entry>) -> impl Future<Item = Entry, Error = Error> 
+    where 
+        F: Future<Item = Fetched, Error = Error> + 'static, { 
+            //  An invalidation that runs while the upstream answers must not be undone by this write 
+            let generation = link.generation();
+            fut.and_then(move |fetched| { 
+                let (entry, data) = prepare(&config.cache, &key, &policy, fetched, previous.as_ref());
+                let (data, expiration) = match data { 
+                    Some(data) => data,
+                    None => return boxed(future::ok(entry)),
+                };
+                let tags: Vec<String> = policy.tags.iter().map(|tag| config.cache.tag(tag)).collect();
+                if !generation.is_current(&key, &tags) { 
+                    debug!("{} was invalidated while it was fetched, not stored", key);
+                    return boxed(future::ok(entry));
+                }
+                let res = link.set_value(&key, &data, expiration, &tags)
+                    .then(move |_|  {
+                        debug!("Cached Updated");
+                        future::ok::<_, Error>(entry)
+                    });
+                boxed(res)
+            })
+        }
+
+//  Turns an upstream response into an entry and the value to store with its hard expiry 
+//  Responses the upstream doesn't allow to store and responses that are too large have no value, they are only returned 
+fn prepare(config: &CacheConfig, key: &str, policy: &CachePolicy, fetched: Fetched, previous: Option<&Entry>) -> (Entry, Option<(Vec<u8>, usize)>) { 
+    //  The max-age of the upstream replaces the ttl of the policy, the stale windows stay the same 
+    let Fetched { body, directives, content_type } = fetched;
+    let ttl = directives.max_age.unwrap_or(policy.ttl);
+    let expiration = policy.expiration(ttl);
+    let mut entry = Entry::new(body, ttl, previous.map(|entry| &entry.meta));
+    entry.meta.content_type = content_type;
+    if directives.no_store || expiration == 0 { 
+        debug!("Upstream response for {} isn't cacheable", key);
+        entry.no_store = true;
+        return (entry, None);
+    }
+    //  Large responses are still returned to the client, they just aren't stored 
+    match entry.encode(config.compression, config.compress_above) { 
+        Ok(ref data) if data.len() > config.max_object_bytes => { 
+            debug!("Response for {} is too large to cache: {} bytes", key, data.len());
+            (entry, None)
+        }
+        Ok(data) => (entry, Some((data, expiration))),
+        Err(err) => { 
+            error!("Can't encode the response for {}: {}", key, err);
+            (entry, None)
+        }
+    }
 }
 
 
@@ -325,8 +944,77 @@ impl Middleware<State> for Counter {
     }
 }
 
+//  Rate limiting middleware 
+//  Every worker counts requests in one second windows and rejects the ones above the configured limit 
+//  The limit is read from the shared configuration for each request, so a reload applies immediately 
+pub struct RateLimit;
+
+impl Middleware<State> for RateLimit { 
+    fn start(&self, req: &HttpRequest<State>) -> Result<Started> { 
+        let limit = req.state().config.get().limits.requests_per_second;
+        if limit == 0 { 
+            return Ok(Started::Done);
+        }
+        let mut window = req.state().window.borrow_mut();
+        if window.0.elapsed() >= Duration::from_secs(1) { 
+            *window = (Instant::now(), 0);
+        }
+        if window.1 >= limit { 
+            return Ok(Started::Response(HttpResponse::new(StatusCode::TOO_MANY_REQUESTS)));
+        }
+        window.1 += 1;
+        Ok(Started::Done)
+    }
+}
+
+//  Admin authentication 
+//  The admin API is only served when a token is configured, and requests have to send it in an Authorization: Bearer header 
+//  The token is read from the shared configuration, so it can be changed with a reload 
+pub struct AdminAuth;
+
+impl Middleware<State> for AdminAuth { 
+    fn start(&self, req: &HttpRequest<State>) -> Result<Started> { 
+        let config = req.state().config.get();
+        let token = match &config.admin.token { 
+            Some(token) => token,
+            None => return Ok(Started::Response(HttpResponse::NotFound().finish())),
+        };
+        let given = req.headers().get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .unwrap_or("");
+        //  The comparison takes the same time wherever the tokens differ 
+        if given.len() == token.len() && openssl::memcmp::eq(given.as_bytes(), token.as_bytes()) { 
+            return Ok(Started::Done);
+        }
+        let resp = HttpResponse::Unauthorized()
+            .header(header::WWW_AUTHENTICATE, "Bearer")
+            .finish();
+        Ok(Started::Response(resp))
+    }
+}
+
 fn main() {
-    env_logger::init();
+    //  The path to the configuration file can be provided as the first argument or with the ROUTER_CONFIG variable 
+    let path = env::args().nth(1)
+        .or_else(|| env::var("ROUTER_CONFIG").ok())
+        .unwrap_or_else(|| "router.toml".to_owned());
+    let path = PathBuf::from(path);
+    let config = Config::load(&path).unwrap_or_else(|err| { 
+        eprintln!("Can't load configuration: {}", err);
+        std::process::exit(1);
+    });
+
+    //  The logger lets everything through and the actual level is controlled with log::set_max_level,
+    //  that way the level can be changed when the configuration is reloaded 
+    let mut logger = env_logger::Builder::new();
+    logger.filter(None, LevelFilter::Trace);
+    if let Ok(filters) = env::var("RUST_LOG") { 
+        logger.parse(&filters);
+    }
+    logger.init();
+    log::set_max_level(config.log_level().unwrap_or(LevelFilter::Info));
+
     let sys = actix::System::new("router");
      //  Creating a new server with the server::new() method expect a closure toreturn the App instance 
     //  You need to set the number of workers or threads to run actors 
@@ -334,16 +1022,101 @@ fn main() {
     //  WE call the start method to start the Server Actor => This will return an Addr struct with an address that you can use to send messages to a Server actor instance 
 
     //  Database Actor 
-    let addr = SyncArbiter::start(3, || { 
-        CacheActor::new("redis://127.0.0.1:6379", 10)
+    //  Redis connections are opened in the background, the router starts even when Redis isn't available yet 
+    //  With Sentinel the list of nodes starts empty, the SentinelActor fills in the master once a sentinel answers 
+    let cache_config = config.cache.clone();
+    let redis = cache_config.backend == CacheKind::Redis;
+    let nodes = if redis { config.cache.redis_nodes().unwrap_or_default() } else { Vec::new() };
+    let nodes = RedisNodes::new(nodes);
+    if let (true, Some(sentinel)) = (redis, &config.cache.sentinel) { 
+        SentinelActor::new(sentinel, nodes.clone()).start();
+    }
+    let backend = cache::backend(&config.cache, &nodes);
+    let addr = CacheActor::new(backend, Breaker::new(config.cache.breaker.clone())).start();
+
+    let address = config.server.address.clone();
+    let workers = config.server.workers;
+    let tls = config.server.tls.clone();
+    let mut listeners = vec![addr.clone().recipient()];
+
+    //  The certificates are loaded before the server starts, the TlsActor replaces them when they change 
+    let certificates = tls.as_ref().map(|tls| { 
+        let certificates = Certificates::new(tls).unwrap_or_else(|err| { 
+            eprintln!("Can't load certificate: {}", err);
+            std::process::exit(1);
+        });
+        let actor = TlsActor::new(tls.clone(), certificates.clone(), config.server.watch_interval);
+        listeners.push(actor.start().recipient());
+        certificates
+    });
+
+    //  Connectors with TLS settings for the upstreams, the UpstreamActor restarts them when the settings change 
+    let clients = UpstreamClients::new(&config.upstreams).unwrap_or_else(|err| { 
+        eprintln!("Can't configure upstreams: {}", err);
+        std::process::exit(1);
     });
+    listeners.push(UpstreamActor::new(config.upstreams.clone(), clients.clone()).start().recipient());
 
+    let shared = SharedConfig::new(config);
     let cache = CacheLink::new(addr);
 
-    let repeater = RepeaterActor::new().start();
+    //  Shared by all workers, so concurrent misses are coalesced across the whole process 
+    let flights = SingleFlight::default();
+    //  Invalidations of any worker or instance keep the fetches that are running from writing old values back 
+    let generations = Generations::default();
+
+    //  Writes to the shared cache invalidate the local caches of all workers, and of other instances through Redis pub/sub 
+    let local = cache_config.local.clone();
+    let instance = cache::instance_id();
+    let publisher = if redis { 
+        Some(PublisherActor::new(nodes.clone()).start())
+    } else { 
+        None
+    };
+    let invalidation = InvalidationActor::new(publisher.clone(), &local.channel, &instance, generations.clone()).start();
+    if redis { 
+        cache::subscribe_invalidations(nodes.clone(), local.channel.clone(), instance.clone(), invalidation.clone());
+    }
+
+    //  The configured paths are written to the cache before they expire, the refresher has no local copies of its own 
+    let tiered = TieredCache::new(cache.clone(), invalidation.clone(), generations.clone(), Duration::from_millis(0), 0);
+    let refresher = CacheRefresherActor::new(tiered, shared.get(), clients.clone(), flights.clone()).start();
+    listeners.push(refresher.recipient());
+
+    //  The ConfigActor pushes reloaded configuration to the live actors 
+    ConfigActor::new(path, shared.clone(), listeners).start();
+
+    //  New comments reach the WebSocket clients of other instances through the same Redis 
+    let channel = shared.get().notifications.channel.clone();
+    let bridge = publisher.map(|publisher| BridgeActor::new(publisher, &channel, &instance).start().recipient());
+    //  Every instance numbers its updates on its own, so each one keeps its history in its own stream 
+    let notifications = shared.get().notifications.clone();
+    let stream = match notifications.stream { 
+        Some(ref key) if redis => Some(RedisStream::new(cache::redis_backend(&cache_config, &nodes, 1), key, notifications.history)),
+        _ => None,
+    };
+    let repeater = RepeaterActor::new(bridge, notifications.history, stream, instance.clone()).start();
+    if redis { 
+        bridge::relay(nodes, channel, instance, repeater.clone());
+    }
 
-    server::new( move || {
-        let state = State::new(cache.clone(), repeater.clone());
+    let secure = tls.is_some();
+    //  A configured key keeps users signed in across restarts and on every instance 
+    let cookie_key = match shared.get().notifications.cookie_key { 
+        Some(ref key) => key.as_bytes().to_vec(),
+        None => { 
+            warn!("No cookie key is configured, users have to sign in again after a restart and on other instances");
+            let mut key = vec![0; 32];
+            openssl::rand::rand_bytes(&mut key).unwrap_or_else(|err| { 
+                eprintln!("Can't generate a cookie key: {}", err);
+                std::process::exit(1);
+            });
+            key
+        }
+    };
+    let server = server::new( move || {
+        let tiered = TieredCache::new(cache.clone(), invalidation.clone(), generations.clone(), Duration::from_millis(local.ttl_ms), local.max_entries);
+        let state = State::new(tiered, repeater.clone(), shared.clone(), clients.clone(), flights.clone());
         //  App creation 
         App::with_state(state)
             //  This helps with log request and responses 
@@ -351,11 +1124,13 @@ fn main() {
              //  Helps identify request using identity backend that implements the IdentityPolicy trait   
             .middleware(IdentityService::new(
                     //  CookieIdentityPolicy expects a key with at least 32 bytes 
-                    CookieIdentityPolicy::new(&[0; 32])
+                    CookieIdentityPolicy::new(&cookie_key)
                     .name("auth-example")
-                    .secure(false),
+                    //  The identity cookie is only sent back over HTTPS when TLS is enabled 
+                    .secure(secure),
                     ))
             .middleware(Counter)
+            .middleware(RateLimit)
 
             //  Scope and Routes 
             //  The next thing to our App instanfce is routing 
@@ -368,13 +1143,29 @@ fn main() {
                     .route("/signin", http::Method::POST, signin)
                     .route("/new_comment", http::Method::POST, new_comment)
                     .route("/comments", http::Method::GET, comments)
+                    .route("/comments/poll", http::Method::GET, poll_comments)
+                    .route("/ws_token", http::Method::GET, ws_token)
+                    .route("/messages", http::Method::POST, send_message)
                      //  if a server taes a request for /api/signup with the POST method, it will call the signup function 
             })
             //  Counter Middleware, to count the total quantity of request:  
             .route("/stats/counter", http::Method::GET, counter)
+            .route("/stats/coalescing", http::Method::GET, coalescing)
+            .route("/stats/cache", http::Method::GET, cache_stats)
+            .route("/stats/notifications", http::Method::GET, notification_stats)
+            .scope("/admin", |scope| { 
+                scope
+                    .middleware(AdminAuth)
+                    .route("/cache/key", http::Method::GET, inspect_key)
+                    .route("/cache/key", http::Method::DELETE, purge_key)
+                    .route("/cache/prefix", http::Method::DELETE, purge_prefix)
+                    .route("/cache/warm", http::Method::POST, warm_key)
+                    .route("/cache/paths", http::Method::GET, path_stats)
+            })
             //  We dont need a scope here since we have only one handler and can call th eroute method directly for the App instanc
             
             .resource("/ws", |r| r.method(http::Method::GET).f(ws_connect))
+            .resource("/events", |r| r.method(http::Method::GET).f(events))
     
             //  Static files handler
                 //  The handler method expects a prefix for a pth and a type that implements the Handler Trait 
@@ -384,12 +1175,198 @@ fn main() {
                  //  SO if a client send a GET request to a path such as /index.html or /css/styles.css, 
                  // then the Static files handler will send the contents of the corresponding files from the ./static/ local folder
             )
-    }).workers(1)
-        .bind("127.0.0.1:8080")
-        .unwrap()
-        .start();
+    }).workers(workers);
+
+    //  With TLS enabled the listener is bound with an OpenSSL acceptor instead of a plain socket 
+    let server = match (&tls, &certificates) { 
+        (Some(tls), Some(certificates)) => { 
+            let acceptor = certificates.acceptor(tls).unwrap();
+            server.bind_ssl(&address, acceptor)
+        }
+        _ => server.bind(&address),
+    };
+    server.unwrap().start();
 
-    println!("Started http server: 127.0.0.1:8080");
+    println!("Started http server: {}", address);
+
+    if let Some(redirect) = tls.and_then(|tls| tls.redirect_address) { 
+        let port = address.parse::<SocketAddr>().unwrap().port();
+        server::new(move || { 
+            App::new()
+                .middleware(middleware::Logger::default())
+                .default_resource(move |r| r.f(move |req| https_redirect(req, port)))
+        }).workers(1)
+            .bind(&redirect)
+            .unwrap()
+            .start();
+        println!("Started redirect server: {}", redirect);
+    }
     //  The server actor won't run until we call run the method of the System instance 
     let _ = sys.run();
 }
+
+#[cfg(test)]
+mod tests { 
+    use actix::SystemService;
+    use actix_web::http::HeaderMap;
+    use actix_web::http::header::HeaderValue;
+    use super::*;
+
+    fn headers(name: header::HeaderName, value: &str) -> HeaderMap { 
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn matching_etag_is_not_modified() { 
+        let entry = Entry::new(b"[]".to_vec(), 10, None);
+        let etag = entry.meta.etag.clone();
+        assert!(not_modified(&headers(header::IF_NONE_MATCH, &etag), &entry));
+        assert!(not_modified(&headers(header::IF_NONE_MATCH, &format!("\"other\", W/{}", etag)), &entry));
+        assert!(not_modified(&headers(header::IF_NONE_MATCH, "*"), &entry));
+        assert!(!not_modified(&headers(header::IF_NONE_MATCH, "\"other\""), &entry));
+        assert!(!not_modified(&HeaderMap::new(), &entry));
+    }
+
+    #[test]
+    fn modification_date_is_compared() { 
+        let entry = Entry::new(b"[]".to_vec(), 10, None);
+        let date = |time: SystemTime| HttpDate::from(time).to_string();
+        let later = entry.last_modified() + Duration::from_secs(60);
+        let earlier = entry.last_modified() - Duration::from_secs(60);
+        assert!(not_modified(&headers(header::IF_MODIFIED_SINCE, &date(entry.last_modified())), &entry));
+        assert!(not_modified(&headers(header::IF_MODIFIED_SINCE, &date(later)), &entry));
+        assert!(!not_modified(&headers(header::IF_MODIFIED_SINCE, &date(earlier)), &entry));
+        assert!(!not_modified(&headers(header::IF_MODIFIED_SINCE, "yesterday"), &entry));
+    }
+
+    #[test]
+    fn entity_tags_win_over_dates() { 
+        let entry = Entry::new(b"[]".to_vec(), 10, None);
+        let mut headers = headers(header::IF_NONE_MATCH, "\"other\"");
+        let date = HttpDate::from(entry.last_modified()).to_string();
+        headers.insert(header::IF_MODIFIED_SINCE, HeaderValue::from_str(&date).unwrap());
+        assert!(!not_modified(&headers, &entry));
+    }
+
+    //  MessageBody stops at 256 KiB by default, larger upstream responses have to reach the cache 
+    #[test]
+    fn large_upstream_responses_are_read() { 
+        const SIZE: usize = 512 * 1024;
+        let mut srv = actix_web::test::TestServer::new(|app| app.handler(|_: &HttpRequest| HttpResponse::Ok().body(vec![b'x'; SIZE])));
+        let url = format!("http://{}/", srv.addr());
+        let fetch = |limit| { 
+            let url = url.clone();
+            future::lazy(move || get_request(ClientConnector::from_registry(), &url, limit))
+        };
+        let fetched = srv.execute(fetch(SIZE)).unwrap();
+        assert_eq!(fetched.body.len(), SIZE);
+        assert!(srv.execute(fetch(SIZE - 1)).is_err());
+    }
+
+    fn notifications() -> NotificationsConfig { 
+        NotificationsConfig { 
+            token_secret: Some("0123456789abcdef0123".to_owned()),
+            origins: vec!["https://app.example.com".to_owned()],
+            ..NotificationsConfig::default()
+        }
+    }
+
+    fn handshake(headers: &[(&'static str, &str)]) -> HttpRequest { 
+        let mut req = actix_web::test::TestRequest::with_header("Host", "example.com:8080");
+        for (name, value) in headers { 
+            req = req.header(*name, *value);
+        }
+        req.finish()
+    }
+
+    #[test]
+    fn pages_of_other_sites_are_refused() { 
+        let config = notifications();
+        assert!(allowed_origin(&handshake(&[]), &config));
+        assert!(allowed_origin(&handshake(&[("Origin", "http://example.com:8080")]), &config));
+        assert!(allowed_origin(&handshake(&[("Origin", "https://app.example.com")]), &config));
+        for origin in &["https://evil.com", "http://example.com", "http://example.com:8080.evil.com", "null", "https://app.example.com:443"] { 
+            assert!(!allowed_origin(&handshake(&[("Origin", origin)]), &config), "{} was allowed", origin);
+        }
+    }
+
+    #[test]
+    fn tokens_come_from_the_headers() { 
+        let config = notifications();
+        let token = token::issue(config.token_secret.as_ref().unwrap(), "alice", 60).unwrap();
+        let bearer = format!("Bearer {}", token);
+        assert_eq!(ws_user(&handshake(&[("Authorization", &bearer)]), &config), Some("alice".to_owned()));
+        let offered = format!("chat, {}, {}", TOKEN_PROTOCOL, token);
+        let req = handshake(&[("Sec-WebSocket-Protocol", &offered)]);
+        assert_eq!(protocol_token(&req), Some(token.as_str()));
+        assert_eq!(ws_user(&req, &config), Some("alice".to_owned()));
+        //  The token has to follow the bearer protocol 
+        assert_eq!(protocol_token(&handshake(&[("Sec-WebSocket-Protocol", &token)])), None);
+        assert_eq!(protocol_token(&handshake(&[("Sec-WebSocket-Protocol", TOKEN_PROTOCOL)])), None);
+        //  Without a secret no token is accepted 
+        let without = NotificationsConfig { token_secret: None, ..notifications() };
+        assert_eq!(ws_user(&handshake(&[("Authorization", &bearer)]), &without), None);
+    }
+
+    #[test]
+    fn tokens_in_the_query_are_ignored() { 
+        let config = notifications();
+        let token = token::issue(config.token_secret.as_ref().unwrap(), "alice", 60).unwrap();
+        let req = actix_web::test::TestRequest::with_uri(&format!("/ws?topics=article/*&token={}", token)).finish();
+        assert_eq!(ws_user(&req, &config), None);
+        assert_eq!(ws_user(&handshake(&[("Authorization", "Bearer forged.1.00")]), &config), None);
+    }
+
+    //  A 304 replaces the stored headers of caches in between, it has to carry the same ones as the 200 
+    #[test]
+    fn not_modified_keeps_the_caching_headers() { 
+        let policy: CachePolicy = toml::from_str("ttl = 10\nstale_if_error = 60\nvary = [\"Accept-Language\"]\nper_user = true").unwrap();
+        let entry = Entry::new(b"[]".to_vec(), 10, None);
+        let full = caching_headers(&mut HttpResponse::Ok(), &policy, &entry).finish();
+        let empty = caching_headers(&mut HttpResponse::NotModified(), &policy, &entry).finish();
+        for resp in &[&full, &empty] { 
+            assert!(resp.headers()[header::CACHE_CONTROL].to_str().unwrap().starts_with("private, max-age="));
+            assert!(resp.headers()[header::CACHE_CONTROL].to_str().unwrap().ends_with(", stale-if-error=60"));
+            assert_eq!(resp.headers()[header::VARY], "Accept-Language");
+        }
+        let mut uncacheable = entry;
+        uncacheable.no_store = true;
+        let empty = caching_headers(&mut HttpResponse::NotModified(), &policy, &uncacheable).finish();
+        assert_eq!(empty.headers()[header::CACHE_CONTROL], "no-store");
+    }
+
+    //  A fetch that started before an invalidation answers after it, the old list must not come back 
+    #[test]
+    fn invalidated_fetches_are_not_stored() { 
+        let config: Config = toml::from_str("[server]\naddress = \"127.0.0.1:8080\"\n[log]\nlevel = \"info\"\n\
+            [upstreams.users]\nurl = \"http://127.0.0.1:8001\"\n[upstreams.comments]\nurl = \"http://127.0.0.1:8003\"\n\
+            [upstreams.comments_writer]\nurl = \"http://127.0.0.1:8004\"\n[cache]\nbackend = \"memory\"\nexpiration = 10\n").unwrap();
+        let config = Arc::new(config);
+        let policy = config.cache.policy("/list");
+        let key = config.cache.plain_key("/list", "");
+        let fetched = || Fetched { body: b"[]".to_vec(), directives: Directives::default(), content_type: None };
+        let res = actix::System::new("generation").block_on(future::lazy(move || { 
+            let backend = cache::backend(&config.cache, &RedisNodes::new(Vec::new()));
+            let link = CacheLink::new(CacheActor::new(backend, Breaker::new(config.cache.breaker.clone())).start());
+            let hub = InvalidationActor::new(None, "invalidations", "test", Generations::default()).start();
+            let cache = TieredCache::new(link, hub, Generations::default(), Duration::from_secs(60), 10);
+            let (tx, rx) = futures::sync::oneshot::channel();
+            let stale = store(cache.clone(), config.clone(), key.clone(), policy.clone(), rx.map_err(|_| error::ErrorBadGateway("Upstream went away")), None);
+            let invalidated = cache.invalidate(Invalidation::Key(key.clone())).from_err();
+            tx.send(fetched()).ok();
+            let read = { 
+                let (cache, key) = (cache.clone(), key.clone());
+                move || cache.get_value(&key).from_err::<Error>()
+            };
+            let skipped = stale.join(invalidated).and_then({ let read = read.clone(); move |_| read() });
+            //  Nothing was invalidated while the next fetch ran 
+            let fresh = move |skipped| store(cache, config, key, policy, future::ok(fetched()), None).and_then(move |_| read()).map(move |stored| (skipped, stored));
+            skipped.and_then(fresh)
+        }));
+        let (skipped, stored) = res.unwrap();
+        assert!(skipped.is_none());
+        assert!(stored.is_some());
+    }
+}
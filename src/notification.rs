@@ -1,9 +1,14 @@
-use actix::{Actor, ActorContext, AsyncContext, Handler, Recipient, StreamHandler}; 
+use actix::{fut, Actor, ActorContext, ActorFuture, Addr, AsyncContext, Handler, StreamHandler, WrapFuture}; 
 //  ActorContext stopts the method Context isntance from breaking connection with the client 
-use actix_web::ws::{Message, ProtocolError, WebsocketContext};
+use actix_web::dev::Drain;
+use actix_web::ws::{CloseCode, CloseReason, Message, ProtocolError, WebsocketContext};
+use log::warn;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 use super::State;
-use crate::repeater::{RepeaterControl, RepeaterUpdate};
+use crate::config::Config;
+use crate::protocol::{Command, Frame, Item, Request};
+use crate::repeater::{self, Connection, Cursor, GetHistory, Listener, Outbox, RepeaterActor, RepeaterControl, RepeaterUpdate};
 
 // I havent used the Handler or StreamHandler for handling messages
 // But I would use StreamHandler when the actor has to process alot of messages
@@ -19,30 +24,188 @@ const PING_TIMEOUT: Duration = Duration::from_secs(60);
 
 //  Notification Actor 
 //  last_ping: keep the timestamp of the latest ping 
-//  This actor also holds the Recipient address to send RepeaterControl messages  
+//  This actor also holds the address of the RepeaterActor to send RepeaterControl messages and the topics the client subscribed to 
+//  Comments sent to the client are counted, the client acknowledges them with their numbers 
+//  A client that reconnects with the cursor of its last comment gets the missed ones first 
+//  Comments wait in a bounded queue while the previous ones are still being written to a slow connection 
+//  Only signed in users connect, the actor keeps the user id and receives the messages sent to that user 
 pub struct NotificationActor  { 
     last_ping: Instant,
-    repeater: Recipient<RepeaterControl>,
+    repeater: Addr<RepeaterActor>,
+    user: String,
+    topics: Vec<String>,
+    since: Option<Cursor>,
+    delivered: u64,
+    acked: u64,
+    outbox: Outbox,
 }
 
 //  Setting the constructor: 
 impl NotificationActor { 
-    pub fn new(repeater: Recipient<RepeaterControl>) -> Self { 
+    pub fn new(repeater: Addr<RepeaterActor>, user: String, topics: Vec<String>, since: Option<Cursor>) -> Self { 
         Self { 
             last_ping: Instant::now(),
             repeater,
+            user,
+            topics,
+            since,
+            delivered: 0,
+            acked: 0,
+            outbox: Outbox::default(),
+        }
+    }
+
+    //  Serializes a frame and sends it to the client 
+    fn send(&self, frame: &Frame, context: &mut WebsocketContext<Self, State>) { 
+        if let Ok(data) = serde_json::to_string(frame) { 
+            context.text(data);
+        }
+    }
+
+    //  Resumes after the cursor, the summary frame follows the missed comments. The topics are subscribed again, which
+    //  changes nothing for a connection that is already subscribed 
+    fn replay(&mut self, id: Option<u64>, since: Cursor, context: &mut WebsocketContext<Self, State>) { 
+        self.resume(since, context, move |act, res, context| { 
+            let frame = match res { 
+                Ok(replayed) => Frame::Resumed { id, replayed: replayed.count, complete: replayed.complete },
+                Err(err) => { 
+                    warn!("Can't resume a WebSocket client: {}", err);
+                    Frame::error(id, "unavailable", "History isn't available")
+                }
+            };
+            act.send(&frame, context);
+        });
+    }
+
+    //  Handles a JSON request of the client, mistakes of the client are answered with error frames and the connection stays open 
+    fn request(&mut self, text: &str, context: &mut WebsocketContext<Self, State>) { 
+        let Request { id, command } = match serde_json::from_str(text) { 
+            Ok(request) => request,
+            Err(err) => { 
+                //  The id is still repeated when only the command is wrong 
+                let id = serde_json::from_str::<serde_json::Value>(text).ok()
+                    .and_then(|value| value.get("id").and_then(|id| id.as_u64()));
+                return self.send(&Frame::error(id, "bad_request", err.to_string()), context);
+            }
+        };
+        match command { 
+            Command::Subscribe { ref topics } | Command::Unsubscribe { ref topics } if !topics.iter().all(|topic| repeater::valid_pattern(topic)) => { 
+                self.send(&Frame::error(id, "invalid_topic", format!("Invalid topic in {:?}", topics)), context);
+            }
+            Command::Subscribe { topics } => { 
+                for topic in topics { 
+                    if !self.topics.contains(&topic) { 
+                        self.topics.push(topic.clone());
+                        self.repeater.do_send(RepeaterControl::Subscribe { topic, listener: Listener::new(context.address()) });
+                    }
+                }
+                self.send(&Frame::Subscribed { id, topics: self.topics.clone() }, context);
+            }
+            Command::Unsubscribe { topics } => { 
+                for topic in topics { 
+                    if self.topics.contains(&topic) { 
+                        self.topics.retain(|current| *current != topic);
+                        self.repeater.do_send(RepeaterControl::Unsubscribe { topic, listener: Listener::new(context.address()) });
+                    }
+                }
+                self.send(&Frame::Subscribed { id, topics: self.topics.clone() }, context);
+            }
+            Command::Ack { delivery } if delivery > self.delivered => { 
+                self.send(&Frame::error(id, "invalid_ack", format!("Only {} comments were delivered", self.delivered)), context);
+            }
+            Command::Ack { delivery } => { 
+                self.acked = self.acked.max(delivery);
+                self.send(&Frame::Acked { id, delivery: self.acked, pending: self.delivered - self.acked }, context);
+            }
+            //  A ping of the client also keeps the connection alive 
+            Command::Ping { payload } => { 
+                self.last_ping = Instant::now();
+                self.send(&Frame::Pong { id, payload }, context);
+            }
+            Command::History { topic, .. } if !repeater::valid_pattern(&topic) => { 
+                self.send(&Frame::error(id, "invalid_topic", format!("Invalid topic {}", topic)), context);
+            }
+            //  The RepeaterActor answers asynchronously, the frame is sent once it does 
+            Command::History { topic, since, epoch, limit } => { 
+                let fut = self.repeater.send(GetHistory { pattern: topic, since: Cursor { epoch, seq: since }, limit })
+                    .into_actor(self)
+                    .then(move |res, act, context| { 
+                        let frame = match res { 
+                            Ok(updates) => { 
+                                let items = updates.into_iter().map(Item::from).collect();
+                                Frame::History { id, items }
+                            }
+                            Err(_) => Frame::error(id, "unavailable", "History isn't available"),
+                        };
+                        act.send(&frame, context);
+                        fut::ok(())
+                    });
+                context.spawn(fut);
+            }
+            Command::Resume { since, epoch } => self.replay(id, Cursor { epoch, seq: since }, context),
         }
     }
 }
+//  Comments are queued in the Outbox and written as comment frames, numbered for the acknowledgements 
+impl Connection for NotificationActor { 
+    fn outbox(&mut self) -> &mut Outbox { 
+        &mut self.outbox
+    }
+
+    fn repeater(&self) -> &Addr<RepeaterActor> { 
+        &self.repeater
+    }
+
+    fn topics(&self) -> &[String] { 
+        &self.topics
+    }
+
+    fn config(context: &Self::Context) -> Arc<Config> { 
+        context.state().config.get()
+    }
+
+    fn write(&mut self, updates: Vec<RepeaterUpdate>, context: &mut Self::Context) { 
+        for update in updates { 
+            self.delivered += 1;
+            let frame = Frame::Comment { 
+                delivery: self.delivered,
+                item: Item::from(update),
+            };
+            self.send(&frame, context);
+        }
+    }
+
+    fn drain(context: &mut Self::Context) -> Drain<Self> { 
+        context.drain()
+    }
+
+    fn disconnect(&mut self, context: &mut Self::Context) { 
+        warn!("Disconnecting a WebSocket client with {} pending comments", self.outbox.len());
+        context.close(Some(CloseReason { code: CloseCode::Again, description: Some("Too many pending comments".to_owned()) }));
+        context.stop();
+    }
+}
 //  Implenting the NotifcationActor 
 impl Actor for NotificationActor  { 
     type Context =  WebsocketContext<Self, State>;
 
-    //  We create a Subscribe message and send it using RepeaterControl
+    //  We create a Subscribe message for every topic and send it using RepeaterControl
     //  We add a task that will be executed on PING)INTERVAL and will sned a ping message using theping method of WebsocketContext
+    //  The topic of the user isn't one of the topics of the client, it can't be unsubscribed 
+    //  A reconnecting client subscribes with the replay, so no comment arrives before the missed ones 
     fn started(&mut self, context: &mut Self::Context) { 
-        let msg = RepeaterControl::Subscribe(context.address().recipient());
-        self.repeater.do_send(msg).ok();
+        let user = repeater::user_topic(&self.user);
+        self.repeater.do_send(RepeaterControl::Subscribe { topic: user, listener: Listener::new(context.address()) });
+        match self.since.take() { 
+            Some(since) => self.replay(None, since, context),
+            None => for topic in &self.topics { 
+                let msg = RepeaterControl::Subscribe { 
+                    topic: topic.clone(),
+                    listener: Listener::new(context.address()),
+                };
+                self.repeater.do_send(msg);
+            },
+        }
         context.run_interval(PING_INTERVAL, |act, context| {
             //  If the interval is larger than out PING_TIMEOUT value, we will interrupt the connection uisng the stop method of the context
             if Instant::now().duration_since(act.last_ping) > PING_TIMEOUT { 
@@ -53,10 +216,10 @@ impl Actor for NotificationActor  {
         });
     }
     //  Stopped method implementation
-    // This prepares an unsubcribe event with the same address of the actor and sends it to RepeaterActor
+    // This prepares a leave event with the same address of the actor and sends it to RepeaterActor, it drops all of its topics
     fn stopped(&mut self, context: &mut Self::Context) { 
-        let msg = RepeaterControl::Unsubscribe(context.address().recipient());
-        self.repeater.do_send(msg).ok();
+        let msg = RepeaterControl::Leave(Listener::new(context.address()));
+        self.repeater.do_send(msg);
     }
 }
 //  Basic Websocket 
@@ -73,8 +236,12 @@ impl StreamHandler<Message, ProtocolError> for NotificationActor {
             Message::Pong(_) => { 
                 self.last_ping = Instant::now();
             }
-            Message::Text(_) => { },
-            Message::Binary(_) => { },
+            Message::Text(text) => { 
+                self.request(&text, context);
+            }
+            Message::Binary(_) => { 
+                self.send(&Frame::error(None, "bad_request", "Requests have to be JSON text frames"), context);
+            }
             Message::Close(_) => { 
                 context.stop();
             }
@@ -87,12 +254,9 @@ impl StreamHandler<Message, ProtocolError> for NotificationActor {
 impl Handler<RepeaterUpdate> for NotificationActor { 
     type Result = ();
     
+    //  The update is queued, flush destructs it into a comment frame, serialises it using the serde_json crate
+    //  and sends it to the client using the text method of WebsocketContext
     fn handle(&mut self, msg: RepeaterUpdate, context: &mut Self::Context) -> Self::Result {
-        let RepeaterUpdate(comment) = msg;
-        //  Destruct a RepeaterUpdate message to get a NewCOmment Value, serialises it to JSON using the serde_json crate
-        if let Ok(data) = serde_json::to_string(&comment) { 
-            //  data is sent the client using the text method of WebsocketContext
-            context.text(data);
-        }
+        self.enqueue(msg, context);
     }
 }
@@ -0,0 +1,194 @@
+//  WebSocket protocol
+//  Clients send JSON requests to change their subscriptions without reconnecting, every request can carry an id
+//  that is repeated in the answer. The server pushes comments, answers requests and reports errors in typed frames
+//  Requests: {"id": 1, "type": "subscribe", "topics": ["article/42", "news/*"]}
+//            {"id": 2, "type": "unsubscribe", "topics": ["news/*"]}
+//            {"id": 3, "type": "ack", "delivery": 7}
+//            {"id": 4, "type": "ping", "payload": "anything"}
+//            {"id": 5, "type": "history", "topic": "article/*", "since": 40, "epoch": "e1", "limit": 20}
+//            {"id": 6, "type": "resume", "since": 42, "epoch": "e1"}
+//  Every comment carries its sequence number and the epoch of the sequence, a cursor is only exact with both of them 
+//  (a sequence number alone is read in the current sequence while its comment is in the history) 
+//  Messages sent to the user of the connection arrive as comments on the topic @<user id>, they have no sequence number (0)
+use serde_derive::{Deserialize, Serialize};
+use crate::NewComment;
+use crate::repeater::RepeaterUpdate;
+
+#[derive(Deserialize)]
+pub struct Request {
+    #[serde(default)]
+    pub id: Option<u64>,
+    #[serde(flatten)]
+    pub command: Command,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Command {
+    Subscribe { topics: Vec<String> },
+    Unsubscribe { topics: Vec<String> },
+    //  Confirms that the client processed every comment up to this delivery number
+    Ack { delivery: u64 },
+    Ping {
+        #[serde(default)]
+        payload: serde_json::Value,
+    },
+    History {
+        topic: String,
+        #[serde(default)]
+        since: u64,
+        #[serde(default)]
+        epoch: Option<String>,
+        #[serde(default = "default_limit")]
+        limit: usize,
+    },
+    //  Sends the comments of the subscribed topics after this sequence number again, after a reconnect
+    Resume {
+        since: u64,
+        #[serde(default)]
+        epoch: Option<String>,
+    },
+}
+
+fn default_limit() -> usize {
+    20
+}
+
+//  A comment together with the topic it was posted to and its sequence number, clients resume after the last one they saw
+#[derive(Serialize)]
+pub struct Item {
+    pub seq: u64,
+    pub epoch: String,
+    pub topic: String,
+    pub comment: NewComment,
+}
+
+impl From<RepeaterUpdate> for Item {
+    fn from(update: RepeaterUpdate) -> Self {
+        let RepeaterUpdate { seq, epoch, topic, comment, .. } = update;
+        Self { seq, epoch, topic, comment }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Frame {
+    //  Comments are numbered for every connection, the client acknowledges them with these numbers
+    Comment {
+        delivery: u64,
+        #[serde(flatten)]
+        item: Item,
+    },
+    //  The topics of the connection after a subscribe or unsubscribe request
+    Subscribed { id: Option<u64>, topics: Vec<String> },
+    Acked { id: Option<u64>, delivery: u64, pending: u64 },
+    Pong { id: Option<u64>, payload: serde_json::Value },
+    //  The latest comments of the matching topics, oldest first
+    History { id: Option<u64>, items: Vec<Item> },
+    //  Sent after the missed comments, complete is false when some of them were too old to be replayed
+    Resumed { id: Option<u64>, replayed: usize, complete: bool },
+    Error { id: Option<u64>, code: &'static str, message: String },
+}
+
+impl Frame {
+    pub fn error(id: Option<u64>, code: &'static str, message: impl Into<String>) -> Self {
+        Frame::Error {
+            id,
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn parse(text: &str) -> Result<Request, serde_json::Error> {
+        serde_json::from_str(text)
+    }
+
+    fn frame(frame: Frame) -> serde_json::Value {
+        serde_json::to_value(&frame).unwrap()
+    }
+
+    #[test]
+    fn requests_are_parsed() {
+        let request = parse(r#"{"id": 1, "type": "subscribe", "topics": ["article/42", "news/*"]}"#).unwrap();
+        assert_eq!(request.id, Some(1));
+        assert!(matches!(request.command, Command::Subscribe { ref topics } if topics == &["article/42", "news/*"]));
+        let request = parse(r#"{"type": "unsubscribe", "topics": []}"#).unwrap();
+        assert_eq!(request.id, None);
+        assert!(matches!(request.command, Command::Unsubscribe { ref topics } if topics.is_empty()));
+        assert!(matches!(parse(r#"{"type": "ack", "delivery": 7}"#).unwrap().command, Command::Ack { delivery: 7 }));
+        assert!(matches!(parse(r#"{"type": "ping"}"#).unwrap().command, Command::Ping { payload: serde_json::Value::Null }));
+        assert!(matches!(parse(r#"{"type": "resume", "since": 42, "epoch": "e1"}"#).unwrap().command,
+            Command::Resume { since: 42, epoch: Some(ref epoch) } if epoch == "e1"));
+    }
+
+    #[test]
+    fn history_requests_have_defaults() {
+        let request = parse(r#"{"id": 5, "type": "history", "topic": "article/*"}"#).unwrap();
+        assert!(matches!(request.command, Command::History { ref topic, since: 0, epoch: None, limit: 20 } if topic == "article/*"));
+        let request = parse(r#"{"type": "history", "topic": "*", "since": 40, "epoch": "e1", "limit": 5}"#).unwrap();
+        assert!(matches!(request.command, Command::History { since: 40, epoch: Some(_), limit: 5, .. }));
+    }
+
+    #[test]
+    fn malformed_requests_are_refused() {
+        let malformed = [
+            "",
+            "subscribe",
+            r#"{"id": 1}"#,
+            r#"{"id": 1, "type": "publish", "topics": []}"#,
+            r#"{"id": 1, "type": "Subscribe", "topics": []}"#,
+            r#"{"id": 1, "type": "subscribe"}"#,
+            r#"{"id": 1, "type": "subscribe", "topics": "article/42"}"#,
+            r#"{"id": "1", "type": "ping"}"#,
+            r#"{"type": "ack", "delivery": -1}"#,
+            r#"{"type": "resume"}"#,
+            r#"{"type": "history", "since": 4}"#,
+        ];
+        for text in &malformed {
+            assert!(parse(text).is_err(), "{:?} was accepted", text);
+        }
+    }
+
+    #[test]
+    fn frames_are_tagged_with_their_type() {
+        let item = Item {
+            seq: 42,
+            epoch: "e1".to_owned(),
+            topic: "article/42".to_owned(),
+            comment: NewComment { uid: "u1".to_owned(), text: "hello".to_owned() },
+        };
+        assert_eq!(frame(Frame::Comment { delivery: 3, item }), json!({
+            "type": "comment", "delivery": 3, "seq": 42, "epoch": "e1", "topic": "article/42",
+            "comment": {"uid": "u1", "text": "hello"},
+        }));
+        assert_eq!(frame(Frame::Resumed { id: None, replayed: 2, complete: false }),
+            json!({"type": "resumed", "id": null, "replayed": 2, "complete": false}));
+        assert_eq!(frame(Frame::Acked { id: Some(3), delivery: 7, pending: 0 }),
+            json!({"type": "acked", "id": 3, "delivery": 7, "pending": 0}));
+    }
+
+    #[test]
+    fn ping_payloads_come_back_in_the_pong() {
+        let request = parse(r#"{"id": 9, "type": "ping", "payload": {"sent": 1700000000, "tags": ["a"]}}"#).unwrap();
+        let payload = match request.command {
+            Command::Ping { payload } => payload,
+            _ => panic!("Not a ping"),
+        };
+        assert_eq!(frame(Frame::Pong { id: request.id, payload }),
+            json!({"type": "pong", "id": 9, "payload": {"sent": 1700000000, "tags": ["a"]}}));
+    }
+
+    #[test]
+    fn error_frames_repeat_the_id() {
+        assert_eq!(frame(Frame::error(Some(4), "invalid_topic", "Invalid topic @alice")),
+            json!({"type": "error", "id": 4, "code": "invalid_topic", "message": "Invalid topic @alice"}));
+        assert_eq!(frame(Frame::error(None, "bad_request", String::from("expected value"))),
+            json!({"type": "error", "id": null, "code": "bad_request", "message": "expected value"}));
+    }
+}
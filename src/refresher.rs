@@ -0,0 +1,81 @@
+//  Cache refresher
+//  The paths listed in the configuration are fetched from their upstreams on an interval and written to the cache before
+//  they expire, so requests find them fresh right after a deploy and don't wait for the upstream when the ttl runs out
+//  Paths are warmed like through the admin API, so the workers drop their local copies as soon as the new value is written
+use actix::prelude::*;
+use futures::Future;
+use log::{debug, info, warn};
+use std::sync::Arc;
+use std::time::Duration;
+use crate::cache::{Entry, SingleFlight, TieredCache};
+use crate::config::{Config, ConfigUpdate, RefreshConfig};
+use crate::upstream::UpstreamClients;
+use crate::warm;
+
+pub struct CacheRefresherActor {
+    link: TieredCache,
+    config: Arc<Config>,
+    clients: UpstreamClients,
+    //  Shared with the workers, a miss that happens during a refresh waits for it instead of fetching again
+    flights: SingleFlight<Entry>,
+    timers: Vec<SpawnHandle>,
+}
+
+impl CacheRefresherActor {
+    pub fn new(link: TieredCache, config: Arc<Config>, clients: UpstreamClients, flights: SingleFlight<Entry>) -> Self {
+        Self {
+            link,
+            config,
+            clients,
+            flights,
+            timers: Vec::new(),
+        }
+    }
+
+    //  Every path is refreshed right away and then on its interval
+    fn schedule(&mut self, context: &mut Context<Self>) {
+        for timer in self.timers.drain(..) {
+            context.cancel_future(timer);
+        }
+        for target in self.config.cache.refresh.clone() {
+            self.refresh(&target);
+            let interval = Duration::from_secs(target.interval);
+            self.timers.push(context.run_interval(interval, move |act, _| act.refresh(&target)));
+        }
+    }
+
+    fn refresh(&self, target: &RefreshConfig) {
+        let fut = warm(self.link.clone(), self.flights.clone(), self.config.clone(), &self.clients, &target.path, &target.query);
+        let path = target.path.clone();
+        Arbiter::spawn(fut.then(move |res| {
+            match res {
+                Ok((_, entry)) => debug!("Refreshed {}, fresh for {}s", path, entry.max_age()),
+                Err(err) => warn!("Can't refresh {}: {}", path, err),
+            }
+            Ok(())
+        }));
+    }
+}
+
+impl Actor for CacheRefresherActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, context: &mut Self::Context) {
+        self.schedule(context);
+    }
+}
+
+//  A reloaded configuration is used for the next refresh, the timers only restart when the list of paths changes
+impl Handler<ConfigUpdate> for CacheRefresherActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: ConfigUpdate, context: &mut Self::Context) -> Self::Result {
+        let ConfigUpdate(config) = msg;
+        let changed = self.config.cache.refresh != config.cache.refresh;
+        self.config = config;
+        if changed {
+            info!("Cache refresh schedule changed, {} paths", self.config.cache.refresh.len());
+            self.schedule(context);
+        }
+    }
+}
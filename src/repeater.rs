@@ -1,37 +1,362 @@
 //  Repeater Actor 
 //  Used to send notifications ot clients, namely subsribers or listeners
 //  THis is a router that resends messages to multiple subscribers 
-use actix::{Actor, Context, Handler, Message, Recipient};
-use std::collections::HashSet;
+use actix::{fut, Actor, ActorFuture, Addr, Arbiter, AsyncContext, Context, Handler, MailboxError, Message, MessageResult, Recipient, ResponseFuture, SpawnHandle, WrapFuture};
+use actix::dev::ToEnvelope;
+use actix::prelude::SendError;
+use actix_web::dev::Drain;
+use futures::{future, Future};
+use futures::sync::oneshot;
+use log::{info, warn};
+use serde_derive::Serialize;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use std::time::Duration;
 use super::NewComment;
+use crate::cache::RedisStream;
+use crate::config::{Config, NotificationsConfig, Overflow};
 
-//  Struct with a listeners field of the HashSet type that contains Recipient instances 
+//  Comments posted without a topic go to this one, and clients that don't choose their topics subscribe to everything 
+pub const DEFAULT_TOPIC: &str = "comments";
+pub const ALL_TOPICS: &str = "*";
+//  Every connection of a signed in user listens to the topic @<user id>, messages to the user are sent there 
+//  Clients can't subscribe to these topics, and they aren't numbered, kept in the history or matched by prefixes 
+const USER_PREFIX: &str = "@";
+//  How often the listeners are checked for actors that stopped without leaving 
+const SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+//  Topics can't contain whitespace or * and can't start with @, patterns are topics that may end with * 
+pub fn valid_topic(topic: &str) -> bool { 
+    !topic.is_empty() && !topic.starts_with(USER_PREFIX) && !topic.contains(|c: char| c.is_whitespace() || c == '*')
+}
+
+pub fn user_topic(user: &str) -> String { 
+    format!("{}{}", USER_PREFIX, user)
+}
+
+pub fn valid_pattern(pattern: &str) -> bool { 
+    pattern == ALL_TOPICS || valid_topic(pattern.strip_suffix('*').unwrap_or(pattern))
+}
+
+pub fn matches(pattern: &str, topic: &str) -> bool { 
+    match pattern.strip_suffix('*') { 
+        Some(prefix) => topic.starts_with(prefix),
+        None => pattern == topic,
+    }
+}
+
+//  Cursor 
+//  Where a client stopped reading: the sequence number of the last update it saw and the epoch that names the sequence 
+//  Every instance numbers its updates on its own and starts a new sequence when it restarts without a stream, 
+//  so a cursor of another epoch can't be resumed exactly, the client gets the whole history and is told it's incomplete 
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Cursor { 
+    pub epoch: Option<String>,
+    pub seq: u64,
+}
+
+impl Cursor { 
+    //  Reads the id of an event, <epoch>:<seq>, an id without the epoch only resolves while its update is in the history 
+    pub fn parse(id: &str) -> Option<Self> { 
+        match id.rsplit_once(':') { 
+            Some((epoch, seq)) => Some(Self { epoch: Some(epoch.to_owned()), seq: seq.parse().ok()? }),
+            None => Some(Self { epoch: None, seq: id.parse().ok()? }),
+        }
+    }
+}
+
+//  Listener 
+//  The Recipient of a subscribed actor together with its address, which tells whether the actor is still running 
+//  A listener that stopped without leaving is found when an update can't be delivered to it, or by the periodic sweep 
+#[derive(Clone)]
+pub struct Listener { 
+    recipient: Recipient<RepeaterUpdate>,
+    connected: Arc<dyn Fn() -> bool + Send + Sync>,
+}
+
+impl Listener { 
+    pub fn new<A>(addr: Addr<A>) -> Self 
+    where 
+        A: Actor + Handler<RepeaterUpdate>,
+        A::Context: ToEnvelope<A, RepeaterUpdate>,
+    { 
+        Self { 
+            recipient: addr.clone().recipient(),
+            connected: Arc::new(move || addr.connected()),
+        }
+    }
+
+    fn connected(&self) -> bool { 
+        (self.connected)()
+    }
+
+    //  Returns false when the mailbox of the actor is closed 
+    fn send(&self, update: RepeaterUpdate) -> bool { 
+        !matches!(self.recipient.do_send(update), Err(SendError::Closed(_)))
+    }
+}
+
+impl PartialEq for Listener { 
+    fn eq(&self, other: &Self) -> bool { 
+        self.recipient == other.recipient
+    }
+}
+
+impl Eq for Listener {}
+
+impl Hash for Listener { 
+    fn hash<H: Hasher>(&self, state: &mut H) { 
+        self.recipient.hash(state);
+    }
+}
+
+//  Listeners subscribe to topics like article/42, a pattern that ends with * subscribes to every topic with that prefix 
+//  Exact topics and prefixes are kept apart, so a message only looks up its own topic and walks the prefixes 
 pub struct RepeaterActor { 
-    listeners: HashSet<Recipient<RepeaterUpdate>>, 
+    topics: HashMap<String, HashSet<Listener>>, 
+    prefixes: HashMap<String, HashSet<Listener>>,
     //  The Recipient type is an address that supports only one type Of MEssages
+    //  The latest updates of all topics, the oldest one is dropped first 
+    history: VecDeque<RepeaterUpdate>,
+    history_size: usize,
+    //  Sequence number of the latest update, clients resume after it when they reconnect 
+    seq: u64,
+    //  Names the sequence, it's restored from the stream together with the history 
+    epoch: String,
+    //  Sends the updates of this instance to the other ones 
+    bridge: Option<Recipient<RepeaterUpdate>>,
+    //  Keeps the history in Redis, so the sequence continues after a restart 
+    stream: Option<RedisStream>,
+    stats: DeliveryStats,
+    //  Long polling requests that wait for new comments 
+    polls: HashMap<u64, Parked>,
+    next_poll: u64,
 }
-//  Add a constructor that creates an empty HashSet: 
+//  Add a constructor that creates empty maps of subscriptions: 
 impl RepeaterActor { 
-    pub fn new() -> Self { 
+    pub fn new(bridge: Option<Recipient<RepeaterUpdate>>, history_size: usize, stream: Option<RedisStream>, epoch: String) -> Self { 
         Self { 
-            listeners: HashSet::new()
+            topics: HashMap::new(),
+            prefixes: HashMap::new(),
+            history: VecDeque::with_capacity(history_size),
+            history_size,
+            seq: 0,
+            epoch,
+            bridge,
+            stream,
+            stats: DeliveryStats::default(),
+            polls: HashMap::new(),
+            next_poll: 0,
+        }
+    }
+
+    fn remember(&mut self, update: RepeaterUpdate) { 
+        if self.history.len() == self.history_size { 
+            self.history.pop_front();
+        }
+        self.history.push_back(update);
+    }
+
+    //  Writes an update to the stream in the background, a failed write only loses it after a restart 
+    fn persist(&self, update: &RepeaterUpdate) { 
+        let stream = match self.stream { 
+            Some(ref stream) => stream,
+            None => return,
+        };
+        let comment = match serde_json::to_string(&update.comment) { 
+            Ok(comment) => comment,
+            Err(_) => return,
+        };
+        let seq = update.seq.to_string();
+        let origin = update.origin.clone().unwrap_or_default();
+        let fields = [("seq", seq.as_str()), ("epoch", update.epoch.as_str()), ("topic", update.topic.as_str()), ("origin", origin.as_str()), ("comment", comment.as_str())];
+        Arbiter::spawn(stream.append(&fields).map_err(|err| warn!("Can't write the notifications history: {}", err)));
+    }
+
+    //  Removes the listener from all of its topics 
+    fn remove(&mut self, listener: &Listener) { 
+        for subscriptions in &mut [&mut self.topics, &mut self.prefixes] { 
+            subscriptions.retain(|_, subscribers| { 
+                subscribers.remove(listener);
+                !subscribers.is_empty()
+            });
+        }
+    }
+
+    fn prune(&mut self, closed: Vec<Listener>) { 
+        if closed.is_empty() { 
+            return;
+        }
+        info!("Removing {} listeners that stopped without leaving", closed.len());
+        self.stats.pruned += closed.len();
+        for listener in &closed { 
+            self.remove(listener);
+        }
+    }
+
+    //  Finds the listeners that stopped while no update was sent to them 
+    fn sweep(&mut self) { 
+        let closed = self.listeners().into_iter().filter(|listener| !listener.connected()).cloned().collect();
+        self.prune(closed);
+    }
+
+    fn listeners(&self) -> HashSet<&Listener> { 
+        self.topics.values().chain(self.prefixes.values()).flatten().collect()
+    }
+
+    //  Whether the cursor names a position of the current sequence. A cursor without the epoch, like ?since=<seq>, is taken
+    //  as one of the current sequence while its update is still in the history, there is no way to tell it apart otherwise 
+    fn resolves(&self, cursor: &Cursor) -> bool { 
+        match cursor.epoch { 
+            Some(ref epoch) => *epoch == self.epoch && cursor.seq <= self.seq,
+            None => cursor.seq <= self.seq 
+                && self.history.front().map(|oldest| oldest.seq <= cursor.seq + 1).unwrap_or(cursor.seq == self.seq),
+        }
+    }
+
+    //  The sequence number to replay after and whether no update after it is missing from the history 
+    //  A cursor that doesn't resolve, of another epoch or ahead of the sequence, replays the whole history 
+    fn position(&self, cursor: &Cursor) -> (u64, bool) { 
+        let known = self.resolves(cursor);
+        let since = if known { cursor.seq } else { 0 };
+        let complete = known && self.history.front().map(|oldest| oldest.seq <= since + 1).unwrap_or(true);
+        (since, complete)
+    }
+
+    //  Updates after since whose topic matches one of the patterns 
+    fn missed(&self, since: u64, patterns: &[String]) -> Vec<RepeaterUpdate> { 
+        self.history.iter()
+            .filter(|update| update.seq > since && patterns.iter().any(|pattern| matches(pattern, &update.topic)))
+            .cloned()
+            .collect()
+    }
+
+    //  Sends a message to the connections of a user, and to the other instances where the user may be connected too 
+    fn direct(&mut self, msg: RepeaterUpdate) { 
+        let closed = match self.topics.get(&msg.topic) { 
+            Some(listeners) => listeners.iter().filter(|listener| !listener.send(msg.clone())).cloned().collect(),
+            None => Vec::new(),
+        };
+        self.prune(closed);
+        if let (None, Some(bridge)) = (&msg.origin, &self.bridge) { 
+            bridge.do_send(msg).ok();
+        }
+    }
+
+    //  Answers the parked polls that wait for the topic 
+    fn wake(&mut self, topic: &str, context: &mut Context<Self>) { 
+        let woken: Vec<u64> = self.polls.iter()
+            .filter(|(_, poll)| poll.patterns.iter().any(|pattern| matches(pattern, topic)))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in woken { 
+            if let Some(poll) = self.polls.remove(&id) { 
+                context.cancel_future(poll.timer);
+                let updates = self.missed(poll.since, &poll.patterns);
+                poll.tx.send(self.polled(updates, poll.complete)).ok();
+            }
+        }
+    }
+
+    fn polled(&self, updates: Vec<RepeaterUpdate>, complete: bool) -> Polled { 
+        Polled { 
+            epoch: self.epoch.clone(),
+            seq: self.seq,
+            updates,
+            complete,
+        }
+    }
+
+    fn subscribe(&mut self, topic: &str, listener: Listener) { 
+        let (subscriptions, key) = self.subscriptions(topic);
+        subscriptions.entry(key).or_default().insert(listener);
+    }
+
+    //  The patterns a listener subscribed to 
+    fn patterns(&self, listener: &Listener) -> (Vec<&str>, Vec<&str>) { 
+        (subscribed(&self.topics, listener), subscribed(&self.prefixes, listener))
+    }
+
+    //  The map a pattern belongs to and the key in it 
+    fn subscriptions(&mut self, pattern: &str) -> (&mut HashMap<String, HashSet<Listener>>, String) { 
+        match pattern.strip_suffix('*') { 
+            Some(prefix) => (&mut self.prefixes, prefix.to_owned()),
+            None => (&mut self.topics, pattern.to_owned()),
         }
     }
 }
+fn subscribed<'a>(subscriptions: &'a HashMap<String, HashSet<Listener>>, listener: &Listener) -> Vec<&'a str> { 
+    subscriptions.iter()
+        .filter(|(_, subscribers)| subscribers.contains(listener))
+        .map(|(key, _)| key.as_str())
+        .collect()
+}
+
 /*
  Implement an Actor trait for this Struct 
  It's enough to have a standard Context type as an associated context type of Actor, because it can work asynchronously
 */
 impl Actor for RepeaterActor {
     type Context = Context<Self>;
+
+    //  Restores the history from the stream, no update is handled before it's loaded, so the sequence never goes back 
+    //  Only the entries of the latest epoch are restored, older ones are from a sequence that was started over 
+    fn started(&mut self, context: &mut Self::Context) { 
+        context.run_interval(SWEEP_INTERVAL, |act, _| act.sweep());
+        let latest = match self.stream { 
+            Some(ref stream) => stream.latest(self.history_size),
+            None => return,
+        };
+        let fut = latest
+            .into_actor(self)
+            .then(|res, act, _| { 
+                match res { 
+                    Ok(entries) => { 
+                        let updates: Vec<RepeaterUpdate> = entries.into_iter().filter_map(RepeaterUpdate::restore).collect();
+                        if let Some(latest) = updates.last() { 
+                            act.epoch = latest.epoch.clone();
+                        }
+                        for update in updates { 
+                            if update.epoch == act.epoch { 
+                                act.seq = act.seq.max(update.seq);
+                                act.remember(update);
+                            }
+                        }
+                        info!("Restored {} notifications, the latest one is {}:{}", act.history.len(), act.epoch, act.seq);
+                    }
+                    Err(err) => warn!("Can't load the notifications history: {}", err),
+                }
+                fut::ok(())
+            });
+        context.wait(fut);
+    }
 }
 
 /*
  Updating the message
- Add a RepeaterUpdate Struct that wraps a NewComment type: 
+ Add a RepeaterUpdate Struct that wraps a NewComment type together with the topic it was posted to: 
 */
+//  The origin is the instance that published the update, it's None for comments posted to this instance 
+//  The sequence number and its epoch are assigned by the RepeaterActor, senders leave them at 0 and empty 
 #[derive(Clone)]
-pub struct RepeaterUpdate(pub NewComment);
+pub struct RepeaterUpdate { 
+    pub seq: u64,
+    pub epoch: String,
+    pub topic: String,
+    pub comment: NewComment,
+    pub origin: Option<String>,
+}
+
+impl RepeaterUpdate { 
+    //  Reads an entry of the stream, entries that can't be read are skipped 
+    fn restore(mut fields: HashMap<String, String>) -> Option<Self> { 
+        let seq = fields.get("seq")?.parse().ok()?;
+        let comment = serde_json::from_str(fields.get("comment")?).ok()?;
+        let origin = fields.remove("origin").filter(|origin| !origin.is_empty());
+        Some(Self { seq, epoch: fields.remove("epoch")?, topic: fields.remove("topic")?, comment, origin })
+    }
+}
 /*
  We derivee the Clone Trait, because we need to clone this message to resend it to multiple subscrbers 
 */
@@ -44,19 +369,43 @@ impl Message for RepeaterUpdate {
 impl Handler<RepeaterUpdate> for RepeaterActor { 
     type Result = ();
 
-    //  Iterate over all listeners and sends a cloned message to them
+    //  Collects the listeners of the topic and of every matching prefix, and sends a cloned message to each of them once 
     //  Actor receives a message and immediately sends it to all known listeners
-    fn handle(&mut self, msg: RepeaterUpdate, _: &mut Self::Context) -> Self::Result {
-        for listener in &self.listeners { 
-            listener.do_send(msg.clone()).ok();
+    fn handle(&mut self, mut msg: RepeaterUpdate, context: &mut Self::Context) -> Self::Result {
+        if msg.topic.starts_with(USER_PREFIX) { 
+            return self.direct(msg);
         }
+        self.seq += 1;
+        msg.seq = self.seq;
+        msg.epoch = self.epoch.clone();
+        let mut listeners: HashSet<&Listener> = HashSet::new();
+        if let Some(subscribers) = self.topics.get(&msg.topic) { 
+            listeners.extend(subscribers);
+        }
+        for (prefix, subscribers) in &self.prefixes { 
+            if msg.topic.starts_with(prefix.as_str()) { 
+                listeners.extend(subscribers);
+            }
+        }
+        let closed = listeners.into_iter().filter(|listener| !listener.send(msg.clone())).cloned().collect();
+        self.prune(closed);
+        //  Only local comments are published, the updates of other instances were already sent to everyone 
+        if let (None, Some(bridge)) = (&msg.origin, &self.bridge) { 
+            bridge.do_send(msg.clone()).ok();
+        }
+        let topic = msg.topic.clone();
+        self.persist(&msg);
+        self.remember(msg);
+        self.wake(&topic, context);
     }
 }
 //  Control Message 
-//  Actors will send their own Recipient addresses to start listening for updates or to stop any notifications about new comments
+//  Actors will send their own Listener addresses to start listening for updates of a topic or to stop any notifications about it
 pub enum RepeaterControl { 
-    Subscribe(Recipient<RepeaterUpdate>),
-    Unsubscribe(Recipient<RepeaterUpdate>)
+    Subscribe { topic: String, listener: Listener },
+    Unsubscribe { topic: String, listener: Listener },
+    //  Removes the listener from all of its topics, it's sent when the listener stops 
+    Leave(Listener),
 }
 //  Implement the Message trait for the RepeaterControl Struct to turn it into the message type and use an empty Result associated type: 
 impl Message for RepeaterControl { 
@@ -67,16 +416,573 @@ impl Handler<RepeaterControl> for RepeaterActor {
 
     fn handle(&mut self, msg: RepeaterControl, _: &mut Self::Context) -> Self::Result { 
         match msg { 
-            //  This adds a new Recipient set on the Subcribe message variant, and removes the Recipient upon Unsubscr
-            RepeaterControl::Subscribe(listener) => { 
-                self.listeners.insert(listener);
+            //  This adds a new Recipient to the topic on the Subcribe message variant, and removes the Recipient upon Unsubscribe 
+            //  Topics without listeners are dropped 
+            RepeaterControl::Subscribe { topic, listener } => self.subscribe(&topic, listener),
+            RepeaterControl::Unsubscribe { topic, listener } => { 
+                let (subscriptions, key) = self.subscriptions(&topic);
+                if let Some(subscribers) = subscriptions.get_mut(&key) { 
+                    subscribers.remove(&listener);
+                    if subscribers.is_empty() { 
+                        subscriptions.remove(&key);
+                    }
+                }
             }
-            RepeaterControl::Unsubscribe(listener) => { 
-                self.listeners.remove(&listener);
+            RepeaterControl::Leave(listener) => self.remove(&listener),
+        }
+    }
+}
+
+//  History 
+//  Returns up to limit of the latest updates after the since sequence whose topic matches the pattern, the oldest first 
+pub struct GetHistory { 
+    pub pattern: String,
+    pub since: Cursor,
+    pub limit: usize,
+}
+
+impl Message for GetHistory { 
+    type Result = Vec<RepeaterUpdate>;
+}
+impl Handler<GetHistory> for RepeaterActor { 
+    type Result = MessageResult<GetHistory>;
+
+    fn handle(&mut self, msg: GetHistory, _: &mut Self::Context) -> Self::Result { 
+        let (since, _) = self.position(&msg.since);
+        let mut updates: Vec<RepeaterUpdate> = self.history.iter().rev()
+            .filter(|update| update.seq > since && matches(&msg.pattern, &update.topic))
+            .take(msg.limit)
+            .cloned()
+            .collect();
+        updates.reverse();
+        MessageResult(updates)
+    }
+}
+
+//  Replay 
+//  A client that reconnects sends the cursor of the last update it saw, the missed updates of its topics are sent
+//  to the listener again, in order and before any new update because they go to the same mailbox 
+//  The topics are subscribed by the same message, so no update arrives both live and replayed 
+pub struct Replay { 
+    pub since: Cursor,
+    pub topics: Vec<String>,
+    pub listener: Listener,
+}
+
+//  Complete is false when older updates were already dropped from the history, or the cursor is of another sequence 
+pub struct Replayed { 
+    pub count: usize,
+    pub complete: bool,
+}
+
+impl Message for Replay { 
+    type Result = Replayed;
+}
+impl Handler<Replay> for RepeaterActor { 
+    type Result = MessageResult<Replay>;
+
+    fn handle(&mut self, msg: Replay, _: &mut Self::Context) -> Self::Result { 
+        for topic in &msg.topics { 
+            self.subscribe(topic, msg.listener.clone());
+        }
+        let (since, complete) = self.position(&msg.since);
+        let (topics, prefixes) = self.patterns(&msg.listener);
+        let mut count = 0;
+        for update in self.history.iter().filter(|update| update.seq > since) { 
+            if topics.contains(&update.topic.as_str()) || prefixes.iter().any(|prefix| update.topic.starts_with(prefix)) { 
+                if !msg.listener.send(update.clone()) { 
+                    break;
+                }
+                count += 1;
             }
         }
+        if !msg.listener.connected() { 
+            self.prune(vec![msg.listener]);
+        }
+        MessageResult(Replayed { count, complete })
     }
 }
 
+//  Slow clients 
+//  Every listener with a connection queues the comments its client hasn't received yet in an Outbox, and reports what the overflow
+//  policy did when the queue was full, the counters are served on /stats/notifications together with the current listeners 
+#[derive(Default)]
+pub struct Outbox { 
+    pending: VecDeque<RepeaterUpdate>,
+    //  Set while the connection is still sending the previous batch 
+    draining: bool,
+    //  Comments the overflow policy dropped so far, a replay that lost some of them isn't complete 
+    dropped: usize,
+}
 
+impl Outbox { 
+    //  Returns the policy that was applied when the queue was full, with Disconnect nothing is queued 
+    pub fn push(&mut self, update: RepeaterUpdate, config: &NotificationsConfig) -> Option<Overflow> { 
+        if self.pending.len() < config.queue { 
+            self.pending.push_back(update);
+            return None;
+        }
+        let overflow = config.overflow(&update.topic);
+        self.dropped += 1;
+        if overflow == Overflow::DropOldest { 
+            self.pending.pop_front();
+            self.pending.push_back(update);
+        }
+        Some(overflow)
+    }
 
+    //  The queued updates, unless the previous batch is still being sent 
+    pub fn batch(&mut self) -> Option<Vec<RepeaterUpdate>> { 
+        if self.draining || self.pending.is_empty() { 
+            return None;
+        }
+        self.draining = true;
+        Some(self.pending.drain(..).collect())
+    }
+
+    //  Everything that is queued, even while the previous batch is being sent 
+    pub fn take(&mut self) -> Vec<RepeaterUpdate> { 
+        self.pending.drain(..).collect()
+    }
+
+    pub fn drained(&mut self) { 
+        self.draining = false;
+    }
+
+    pub fn len(&self) -> usize { 
+        self.pending.len()
+    }
+
+    pub fn dropped(&self) -> usize { 
+        self.dropped
+    }
+}
+
+//  Connection 
+//  What WebSocket connections and event streams share: updates wait in the Outbox and are written in batches, the next batch
+//  waits until the connection sent the previous one. A connection only tells how it writes updates and how it's closed 
+pub trait Connection: Actor + Handler<RepeaterUpdate> 
+where 
+    Self::Context: AsyncContext<Self> + ToEnvelope<Self, RepeaterUpdate>,
+{ 
+    fn outbox(&mut self) -> &mut Outbox;
+    fn repeater(&self) -> &Addr<RepeaterActor>;
+    fn topics(&self) -> &[String];
+    fn config(context: &Self::Context) -> Arc<Config>;
+    fn write(&mut self, updates: Vec<RepeaterUpdate>, context: &mut Self::Context);
+    //  Resolves once everything written so far was sent 
+    fn drain(context: &mut Self::Context) -> Drain<Self>;
+    //  Closes a connection whose queue is full with the Disconnect policy, the client reconnects with its cursor 
+    fn disconnect(&mut self, context: &mut Self::Context);
+
+    //  Queues an update, the overflow policy of its topic decides what happens when the queue is full 
+    fn enqueue(&mut self, update: RepeaterUpdate, context: &mut Self::Context) { 
+        let config = Self::config(context);
+        if let Some(overflow) = self.outbox().push(update, &config.notifications) { 
+            self.repeater().do_send(Dropped(overflow));
+            if overflow == Overflow::Disconnect { 
+                self.disconnect(context);
+                return;
+            }
+        }
+        self.flush(context);
+    }
+
+    fn flush(&mut self, context: &mut Self::Context) { 
+        let batch = match self.outbox().batch() { 
+            Some(batch) => batch,
+            None => return,
+        };
+        self.write(batch, context);
+        let fut = Self::drain(context).then(|_, act: &mut Self, context| { 
+            act.outbox().drained();
+            act.flush(context);
+            fut::ok(())
+        });
+        context.spawn(fut);
+    }
+
+    //  Asks the RepeaterActor for the missed updates, they arrive as usual updates. Once the ones that still wait in the queue
+    //  are written, resumed gets the summary, which isn't complete when the queue dropped some of them in the meantime 
+    fn resume<F>(&mut self, since: Cursor, context: &mut Self::Context, resumed: F) 
+    where 
+        F: FnOnce(&mut Self, Result<Replayed, MailboxError>, &mut Self::Context) + 'static,
+    { 
+        let msg = Replay { since, topics: self.topics().to_vec(), listener: Listener::new(context.address()) };
+        let dropped = self.outbox().dropped();
+        let fut = self.repeater().send(msg)
+            .into_actor(self)
+            .then(move |res, act, context| { 
+                let pending = act.outbox().take();
+                act.write(pending, context);
+                let res = res.map(|replayed| Replayed { 
+                    count: replayed.count,
+                    complete: replayed.complete && act.outbox().dropped() == dropped,
+                });
+                resumed(act, res, context);
+                fut::ok(())
+            });
+        context.spawn(fut);
+    }
+}
+
+#[derive(Clone, Default, Serialize)]
+pub struct DeliveryStats { 
+    pub subscribers: usize,
+    pub subscriptions: usize,
+    //  Long polling requests that currently wait 
+    pub polls: usize,
+    //  Listeners removed because they stopped without leaving 
+    pub pruned: usize,
+    pub dropped_oldest: usize,
+    pub dropped_newest: usize,
+    pub disconnected: usize,
+}
+
+pub struct Dropped(pub Overflow);
+
+impl Message for Dropped { 
+    type Result = ();
+}
+impl Handler<Dropped> for RepeaterActor { 
+    type Result = ();
+
+    fn handle(&mut self, Dropped(overflow): Dropped, _: &mut Self::Context) -> Self::Result { 
+        match overflow { 
+            Overflow::DropOldest => self.stats.dropped_oldest += 1,
+            Overflow::DropNewest => self.stats.dropped_newest += 1,
+            Overflow::Disconnect => self.stats.disconnected += 1,
+        }
+    }
+}
+
+pub struct GetDeliveryStats;
+
+impl Message for GetDeliveryStats { 
+    type Result = DeliveryStats;
+}
+impl Handler<GetDeliveryStats> for RepeaterActor { 
+    type Result = MessageResult<GetDeliveryStats>;
+
+    fn handle(&mut self, _: GetDeliveryStats, _: &mut Self::Context) -> Self::Result { 
+        let mut stats = self.stats.clone();
+        stats.subscribers = self.listeners().len();
+        stats.polls = self.polls.len();
+        stats.subscriptions = self.topics.values().chain(self.prefixes.values()).map(HashSet::len).sum();
+        MessageResult(stats)
+    }
+}
+
+//  Long polling 
+//  A poll is answered right away when the history has comments after since, otherwise it's parked until a matching comment
+//  arrives or the timeout expires. A poll without since waits for the next comment 
+//  A cursor without the epoch that doesn't resolve is refused: answering with the whole history would make a client that
+//  keeps polling with it receive the history again and again 
+pub struct Poll { 
+    pub since: Option<Cursor>,
+    pub patterns: Vec<String>,
+    pub timeout: Duration,
+}
+
+//  The cursor of the latest update, the client polls after it next time 
+//  Complete is false when comments after since were lost, like for a replay 
+pub struct Polled { 
+    pub epoch: String,
+    pub seq: u64,
+    pub updates: Vec<RepeaterUpdate>,
+    pub complete: bool,
+}
+
+struct Parked { 
+    since: u64,
+    complete: bool,
+    patterns: Vec<String>,
+    tx: oneshot::Sender<Polled>,
+    timer: SpawnHandle,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PollError { 
+    UnknownCursor,
+    //  The repeater stopped before the poll was answered 
+    Canceled,
+}
+
+impl Message for Poll { 
+    type Result = Result<Polled, PollError>;
+}
+impl Handler<Poll> for RepeaterActor { 
+    type Result = ResponseFuture<Polled, PollError>;
+
+    fn handle(&mut self, msg: Poll, context: &mut Self::Context) -> Self::Result { 
+        if let Some(ref cursor) = msg.since { 
+            if cursor.epoch.is_none() && !self.resolves(cursor) { 
+                return Box::new(future::err(PollError::UnknownCursor));
+            }
+        }
+        let (since, complete) = match msg.since { 
+            Some(ref cursor) => self.position(cursor),
+            None => (self.seq, true),
+        };
+        let updates = self.missed(since, &msg.patterns);
+        if !updates.is_empty() { 
+            return Box::new(future::ok(self.polled(updates, complete)));
+        }
+        let (tx, rx) = oneshot::channel();
+        let id = self.next_poll;
+        self.next_poll += 1;
+        //  The request may be gone already, then nobody receives the answer 
+        let timer = context.run_later(msg.timeout, move |act, _| { 
+            if let Some(poll) = act.polls.remove(&id) { 
+                poll.tx.send(act.polled(Vec::new(), poll.complete)).ok();
+            }
+        });
+        self.polls.insert(id, Parked { since, complete, patterns: msg.patterns, tx, timer });
+        Box::new(rx.map_err(|_| PollError::Canceled))
+    }
+}
+
+#[cfg(test)]
+mod tests { 
+    use super::*;
+    use actix::ActorContext;
+
+    fn update(seq: u64) -> RepeaterUpdate { 
+        RepeaterUpdate { 
+            seq,
+            epoch: "e1".to_owned(),
+            topic: DEFAULT_TOPIC.to_owned(),
+            comment: NewComment { uid: "u1".to_owned(), text: seq.to_string() },
+            origin: None,
+        }
+    }
+
+    fn cursor(epoch: Option<&str>, seq: u64) -> Cursor { 
+        Cursor { epoch: epoch.map(str::to_owned), seq }
+    }
+
+    fn outbox(queue: usize, overflow: Overflow) -> (Outbox, NotificationsConfig) { 
+        let mut config = NotificationsConfig { queue, overflow, ..NotificationsConfig::default() };
+        config.topics.insert("alerts/*".to_owned(), Overflow::Disconnect);
+        (Outbox::default(), config)
+    }
+
+    fn pending(outbox: &mut Outbox) -> Vec<u64> { 
+        outbox.take().into_iter().map(|update| update.seq).collect()
+    }
+
+    #[test]
+    fn full_outbox_drops_the_oldest() { 
+        let (mut outbox, config) = outbox(2, Overflow::DropOldest);
+        assert_eq!(outbox.push(update(1), &config), None);
+        assert_eq!(outbox.push(update(2), &config), None);
+        assert_eq!(outbox.push(update(3), &config), Some(Overflow::DropOldest));
+        assert_eq!(outbox.dropped(), 1);
+        assert_eq!(pending(&mut outbox), vec![2, 3]);
+    }
+
+    #[test]
+    fn full_outbox_drops_the_newest() { 
+        let (mut outbox, config) = outbox(2, Overflow::DropNewest);
+        outbox.push(update(1), &config);
+        outbox.push(update(2), &config);
+        assert_eq!(outbox.push(update(3), &config), Some(Overflow::DropNewest));
+        assert_eq!(pending(&mut outbox), vec![1, 2]);
+    }
+
+    #[test]
+    fn topic_policy_overrides_the_default() { 
+        let (mut outbox, config) = outbox(1, Overflow::DropNewest);
+        outbox.push(update(1), &config);
+        let mut alert = update(2);
+        alert.topic = "alerts/disk".to_owned();
+        assert_eq!(outbox.push(alert, &config), Some(Overflow::Disconnect));
+        assert_eq!(outbox.len(), 1);
+        assert_eq!(outbox.dropped(), 1);
+    }
+
+    #[test]
+    fn batches_wait_for_the_previous_one() { 
+        let (mut outbox, config) = outbox(4, Overflow::DropOldest);
+        outbox.push(update(1), &config);
+        assert_eq!(outbox.batch().map(|batch| batch.len()), Some(1));
+        outbox.push(update(2), &config);
+        assert!(outbox.batch().is_none());
+        outbox.drained();
+        assert_eq!(outbox.batch().map(|batch| batch.len()), Some(1));
+        outbox.drained();
+        assert!(outbox.batch().is_none());
+    }
+
+    #[test]
+    fn event_ids_are_cursors() { 
+        assert_eq!(Cursor::parse("e1:42"), Some(cursor(Some("e1"), 42)));
+        assert_eq!(Cursor::parse("1234-5678:7"), Some(cursor(Some("1234-5678"), 7)));
+        assert_eq!(Cursor::parse("42"), Some(cursor(None, 42)));
+        assert_eq!(Cursor::parse("e1:x"), None);
+        assert_eq!(Cursor::parse(""), None);
+    }
+
+    #[test]
+    fn only_cursors_of_the_same_epoch_resume() { 
+        let mut repeater = RepeaterActor::new(None, 3, None, "e1".to_owned());
+        for seq in 1..=5 { 
+            repeater.remember(update(seq));
+        }
+        repeater.seq = 5;
+        assert_eq!(repeater.position(&cursor(Some("e1"), 4)), (4, true));
+        assert_eq!(repeater.position(&cursor(Some("e1"), 5)), (5, true));
+        //  The oldest kept update is 3, so nothing after 2 is missing 
+        assert_eq!(repeater.position(&cursor(Some("e1"), 2)), (2, true));
+        assert_eq!(repeater.position(&cursor(Some("e1"), 1)), (1, false));
+        //  Another sequence or a cursor ahead of the sequence replay everything 
+        assert_eq!(repeater.position(&cursor(Some("e2"), 4)), (0, false));
+        assert_eq!(repeater.position(&cursor(Some("e1"), 9)), (0, false));
+        //  Without the epoch only a cursor in the history resolves 
+        assert_eq!(repeater.position(&cursor(None, 4)), (4, true));
+        assert_eq!(repeater.position(&cursor(None, 2)), (2, true));
+        assert_eq!(repeater.position(&cursor(None, 1)), (0, false));
+        assert_eq!(repeater.position(&cursor(None, 9)), (0, false));
+        assert_eq!(repeater.missed(3, &[ALL_TOPICS.to_owned()]).len(), 2);
+    }
+
+    //  A client polling with ?since=<seq> has to be parked, not answered with the whole history every time 
+    #[test]
+    fn polls_without_the_epoch_park_or_fail() { 
+        let mut repeater = RepeaterActor::new(None, 3, None, "e1".to_owned());
+        for seq in 1..=5 { 
+            repeater.remember(update(seq));
+        }
+        repeater.seq = 5;
+        let poll = |seq| Poll { since: Some(cursor(None, seq)), patterns: vec![ALL_TOPICS.to_owned()], timeout: Duration::from_millis(10) };
+        let mut system = actix::System::new("poll");
+        let addr = repeater.start();
+        let polled = system.block_on(addr.send(poll(5))).unwrap().unwrap();
+        assert!(polled.updates.is_empty() && polled.complete);
+        assert_eq!((polled.epoch.as_str(), polled.seq), ("e1", 5));
+        let polled = system.block_on(addr.send(poll(3))).unwrap().unwrap();
+        assert_eq!(polled.updates.iter().map(|update| update.seq).collect::<Vec<_>>(), vec![4, 5]);
+        assert_eq!(system.block_on(addr.send(poll(1))).unwrap().err(), Some(PollError::UnknownCursor));
+        assert_eq!(system.block_on(addr.send(poll(9))).unwrap().err(), Some(PollError::UnknownCursor));
+    }
+
+    #[test]
+    fn topics_and_patterns_are_validated() { 
+        let topics = [
+            ("article/42", true),
+            ("comments", true),
+            ("", false),
+            ("article 42", false),
+            ("article/\t", false),
+            ("article/*", false),
+            ("*", false),
+            ("@alice", false),
+            ("a@b", true),
+        ];
+        for (topic, valid) in &topics { 
+            assert_eq!(valid_topic(topic), *valid, "topic {:?}", topic);
+        }
+        let patterns = [
+            ("*", true),
+            ("article/*", true),
+            ("article/42", true),
+            ("article*", true),
+            ("", false),
+            ("**", false),
+            ("*/42", false),
+            ("article/*/comments", false),
+            ("article/**", false),
+            ("@*", false),
+            ("@alice", false),
+        ];
+        for (pattern, valid) in &patterns { 
+            assert_eq!(valid_pattern(pattern), *valid, "pattern {:?}", pattern);
+        }
+    }
+
+    #[test]
+    fn patterns_match_topics_exactly_or_by_prefix() { 
+        let cases = [
+            ("article/42", "article/42", true),
+            ("article/42", "article/420", false),
+            ("article/42", "article/4", false),
+            ("article/*", "article/42", true),
+            ("article/*", "article/", true),
+            ("article/*", "article", false),
+            ("article/*", "news/42", false),
+            ("article*", "articles/1", true),
+            ("*", "news/1", true),
+            ("*", "", true),
+            ("comments", "", false),
+        ];
+        for (pattern, topic, matching) in &cases { 
+            assert_eq!(matches(pattern, topic), *matching, "{:?} and {:?}", pattern, topic);
+        }
+    }
+
+    //  Stops right away, like a connection that went away without leaving 
+    struct Gone;
+
+    impl Actor for Gone { 
+        type Context = Context<Self>;
+
+        fn started(&mut self, context: &mut Self::Context) { 
+            context.stop();
+        }
+    }
+
+    struct Live;
+
+    impl Actor for Live { 
+        type Context = Context<Self>;
+    }
+
+    impl Handler<RepeaterUpdate> for Gone { 
+        type Result = ();
+
+        fn handle(&mut self, _: RepeaterUpdate, _: &mut Self::Context) -> Self::Result {}
+    }
+
+    impl Handler<RepeaterUpdate> for Live { 
+        type Result = ();
+
+        fn handle(&mut self, _: RepeaterUpdate, _: &mut Self::Context) -> Self::Result {}
+    }
+
+    //  The update that can't be delivered shows that the actor is gone 
+    fn gone(system: &mut actix::SystemRunner) -> Listener { 
+        let addr = Gone.start();
+        assert!(system.block_on(addr.send(update(1))).is_err());
+        Listener::new(addr)
+    }
+
+    #[test]
+    fn stopped_listeners_are_pruned_on_delivery() { 
+        let mut system = actix::System::new("prune");
+        let listener = gone(&mut system);
+        let repeater = RepeaterActor::new(None, 3, None, "e1".to_owned()).start();
+        repeater.do_send(RepeaterControl::Subscribe { topic: "article/*".to_owned(), listener });
+        repeater.do_send(RepeaterControl::Subscribe { topic: "news/1".to_owned(), listener: Listener::new(Live.start()) });
+        let mut comment = update(0);
+        comment.topic = "article/42".to_owned();
+        repeater.do_send(comment);
+        let stats = system.block_on(repeater.send(GetDeliveryStats)).unwrap();
+        assert_eq!((stats.pruned, stats.subscribers, stats.subscriptions), (1, 1, 1));
+    }
+
+    #[test]
+    fn sweep_finds_stopped_listeners() { 
+        let mut system = actix::System::new("sweep");
+        let mut repeater = RepeaterActor::new(None, 3, None, "e1".to_owned());
+        let live = Listener::new(Live.start());
+        repeater.subscribe("article/42", gone(&mut system));
+        repeater.subscribe("news/*", gone(&mut system));
+        repeater.subscribe("article/42", live.clone());
+        repeater.sweep();
+        assert_eq!(repeater.stats.pruned, 2);
+        assert!(repeater.listeners().into_iter().eq(vec![&live]));
+        assert!(repeater.prefixes.is_empty());
+        repeater.sweep();
+        assert_eq!(repeater.stats.pruned, 2);
+    }
+}
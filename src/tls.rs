@@ -0,0 +1,246 @@
+//  TLS termination
+//  The router can serve HTTPS with the certificate and key from the configuration and optionally verify client certificates of internal callers
+//  OpenSSL lets us switch the context of a connection in the servername callback, we use it to swap in a reloaded certificate without restarting the listener
+use actix::prelude::*;
+use failure::Error;
+use log::{error, info};
+use openssl::ssl::{AlpnError, SslAcceptor, SslAcceptorBuilder, SslContext, SslFiletype, SslMethod, SslVerifyMode};
+use openssl::x509::X509Name;
+use std::fs;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use crate::config::{ClientAuth, ConfigUpdate, TlsConfig};
+
+//  Fills a builder with the certificate chain, the private key and the client verification settings
+fn builder(config: &TlsConfig) -> Result<SslAcceptorBuilder, Error> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    builder.set_certificate_chain_file(&config.certificate)?;
+    builder.set_private_key_file(&config.private_key, SslFiletype::PEM)?;
+    builder.check_private_key()?;
+    //  Sessions can only be resumed within the same context id when clients are verified
+    builder.set_session_id_context(b"router")?;
+    if let Some(ca) = &config.client_ca {
+        builder.set_ca_file(ca)?;
+        builder.set_client_ca_list(X509Name::load_client_ca_file(ca)?);
+        let mode = match config.client_auth {
+            ClientAuth::None => SslVerifyMode::NONE,
+            ClientAuth::Optional => SslVerifyMode::PEER,
+            ClientAuth::Required => SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+        };
+        builder.set_verify(mode);
+    }
+    Ok(builder)
+}
+
+//  Builds a context that replaces the one of the listener, it needs the same ALPN selection that actix-web installs on the acceptor
+pub fn context(config: &TlsConfig) -> Result<SslContext, Error> {
+    let mut builder = builder(config)?;
+    builder.set_alpn_select_callback(|_, protos| {
+        const H2: &[u8] = b"\x02h2";
+        if protos.windows(3).any(|window| window == H2) {
+            Ok(b"h2")
+        } else {
+            Err(AlpnError::NOACK)
+        }
+    });
+    Ok(builder.build().into_context())
+}
+
+//  The context currently used for new connections, shared between the acceptor and the TlsActor
+#[derive(Clone)]
+pub struct Certificates(Arc<RwLock<SslContext>>);
+
+impl Certificates {
+    pub fn new(config: &TlsConfig) -> Result<Self, Error> {
+        Ok(Certificates(Arc::new(RwLock::new(context(config)?))))
+    }
+
+    //  Creates the builder for HttpServer::bind_ssl, every handshake switches to the latest loaded context
+    pub fn acceptor(&self, config: &TlsConfig) -> Result<SslAcceptorBuilder, Error> {
+        let mut builder = builder(config)?;
+        let current = self.0.clone();
+        builder.set_servername_callback(move |ssl, _| {
+            let context = current.read().unwrap();
+            ssl.set_ssl_context(&context).ok();
+            Ok(())
+        });
+        Ok(builder)
+    }
+
+    fn replace(&self, context: SslContext) {
+        *self.0.write().unwrap() = context;
+    }
+}
+
+//  TLS Actor
+//  Reloads the certificate when the configuration changes or when the certificate files are replaced on disk
+pub struct TlsActor {
+    config: TlsConfig,
+    modified: Vec<Option<SystemTime>>,
+    certificates: Certificates,
+    interval: u64,
+}
+
+impl TlsActor {
+    pub fn new(config: TlsConfig, certificates: Certificates, interval: u64) -> Self {
+        let modified = modified(&config);
+        Self {
+            config,
+            modified,
+            certificates,
+            interval,
+        }
+    }
+
+    fn reload(&mut self) {
+        match context(&self.config) {
+            Ok(context) => {
+                self.certificates.replace(context);
+                info!("Certificate reloaded from {}", self.config.certificate);
+            }
+            Err(err) => {
+                error!("Certificate rejected, keeping the old one: {}", err);
+            }
+        }
+    }
+}
+
+fn modified(config: &TlsConfig) -> Vec<Option<SystemTime>> {
+    [&config.certificate, &config.private_key].iter()
+        .map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok())
+        .collect()
+}
+
+impl Actor for TlsActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, context: &mut Self::Context) {
+        if self.interval > 0 {
+            context.run_interval(Duration::from_secs(self.interval), |act, _| {
+                let modified = modified(&act.config);
+                if modified != act.modified {
+                    act.modified = modified;
+                    act.reload();
+                }
+            });
+        }
+    }
+}
+
+impl Handler<ConfigUpdate> for TlsActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: ConfigUpdate, _: &mut Self::Context) -> Self::Result {
+        let ConfigUpdate(config) = msg;
+        if let Some(tls) = &config.server.tls {
+            self.config = tls.clone();
+            self.modified = modified(&self.config);
+            self.reload();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::asn1::Asn1Time;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::ssl::SslConnector;
+    use openssl::x509::{X509, X509NameBuilder};
+    use std::net::{TcpListener, TcpStream};
+    use std::path::{Path, PathBuf};
+    use std::thread;
+
+    //  Every test writes its certificates to its own directory
+    fn directory(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("router-tls-{}-{}", std::process::id(), test));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    //  Writes a self-signed certificate and its key, the common name tells them apart
+    fn self_signed(dir: &Path, name: &str) -> TlsConfig {
+        let key = PKey::from_ec_key(EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap()).unwrap();
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_text("CN", name).unwrap();
+        let subject = subject.build();
+        let mut certificate = X509::builder().unwrap();
+        certificate.set_version(2).unwrap();
+        certificate.set_subject_name(&subject).unwrap();
+        certificate.set_issuer_name(&subject).unwrap();
+        certificate.set_pubkey(&key).unwrap();
+        certificate.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        certificate.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        certificate.sign(&key, MessageDigest::sha256()).unwrap();
+        let config = TlsConfig {
+            certificate: dir.join(format!("{}.pem", name)).to_string_lossy().into_owned(),
+            private_key: dir.join(format!("{}-key.pem", name)).to_string_lossy().into_owned(),
+            client_ca: None,
+            client_auth: ClientAuth::None,
+            redirect_address: None,
+        };
+        fs::write(&config.certificate, certificate.build().to_pem().unwrap()).unwrap();
+        fs::write(&config.private_key, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        config
+    }
+
+    //  Makes a handshake with the acceptor and returns the common name of the certificate it presented
+    fn presented(certificates: &Certificates, config: &TlsConfig) -> String {
+        let acceptor = certificates.acceptor(config).unwrap().build();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            acceptor.accept(stream).is_ok()
+        });
+        //  The certificates are self-signed, the test only looks at which one is presented
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        let stream = connector.build().connect("localhost", TcpStream::connect(address).unwrap()).unwrap();
+        assert!(server.join().unwrap());
+        let certificate = stream.ssl().peer_certificate().unwrap();
+        let name = certificate.subject_name().entries_by_nid(Nid::COMMONNAME).next().unwrap();
+        name.data().as_utf8().unwrap().to_string()
+    }
+
+    #[test]
+    fn reloaded_certificates_are_used_for_new_connections() {
+        let dir = directory("reload");
+        let first = self_signed(&dir, "first");
+        let second = self_signed(&dir, "second");
+        let certificates = Certificates::new(&first).unwrap();
+        assert_eq!(presented(&certificates, &first), "first");
+        let mut actor = TlsActor::new(second, certificates.clone(), 0);
+        actor.reload();
+        //  The listener keeps its acceptor, the servername callback switches to the new context
+        assert_eq!(presented(&certificates, &first), "second");
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn broken_certificates_keep_the_old_one() {
+        let dir = directory("broken");
+        let config = self_signed(&dir, "current");
+        let certificates = Certificates::new(&config).unwrap();
+        let mut actor = TlsActor::new(config.clone(), certificates.clone(), 0);
+        fs::write(&config.certificate, "not a certificate").unwrap();
+        actor.reload();
+        assert_eq!(presented(&certificates, &self_signed(&dir, "listener")), "current");
+        fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn mismatched_keys_are_refused() {
+        let dir = directory("mismatch");
+        let first = self_signed(&dir, "first");
+        let second = self_signed(&dir, "second");
+        let mismatched = TlsConfig { private_key: second.private_key.clone(), ..first.clone() };
+        assert!(Certificates::new(&mismatched).is_err());
+        let missing = TlsConfig { certificate: dir.join("missing.pem").to_string_lossy().into_owned(), ..first };
+        assert!(Certificates::new(&missing).is_err());
+        fs::remove_dir_all(dir).ok();
+    }
+}
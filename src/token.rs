@@ -0,0 +1,90 @@
+//  WebSocket tokens
+//  Clients that can't send the identity cookie with the WebSocket handshake get a short-lived token from /api/ws_token instead
+//  A token carries the user id and its expiration signed with HMAC-SHA256 and the configured secret: <user>.<expires>.<signature>
+use failure::Error;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0)
+}
+
+fn sign(secret: &str, payload: &str) -> Result<String, Error> {
+    let key = PKey::hmac(secret.as_bytes())?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
+    signer.update(payload.as_bytes())?;
+    Ok(signer.sign_to_vec()?.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+pub fn issue(secret: &str, user: &str, ttl: u64) -> Result<String, Error> {
+    let payload = format!("{}.{}", user, now() + ttl);
+    let signature = sign(secret, &payload)?;
+    Ok(format!("{}.{}", payload, signature))
+}
+
+//  Returns the user id of a valid token that hasn't expired, the user id itself may contain dots
+pub fn verify(secret: &str, token: &str) -> Option<String> {
+    let (payload, signature) = token.rsplit_once('.')?;
+    let expected = sign(secret, payload).ok()?;
+    if signature.len() != expected.len() || !memcmp::eq(signature.as_bytes(), expected.as_bytes()) {
+        return None;
+    }
+    let (user, expires) = payload.rsplit_once('.')?;
+    if expires.parse::<u64>().ok()? < now() || user.is_empty() {
+        return None;
+    }
+    Some(user.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "0123456789abcdef0123";
+
+    #[test]
+    fn round_trip() {
+        let token = issue(SECRET, "alice", 60).unwrap();
+        assert_eq!(verify(SECRET, &token), Some("alice".to_owned()));
+    }
+
+    #[test]
+    fn user_ids_may_contain_dots() {
+        let token = issue(SECRET, "alice.smith@example.com", 60).unwrap();
+        assert_eq!(verify(SECRET, &token), Some("alice.smith@example.com".to_owned()));
+    }
+
+    #[test]
+    fn expired() {
+        //  issue can't make a token that already expired, the payload is signed by hand
+        let payload = format!("alice.{}", now() - 1);
+        let token = format!("{}.{}", payload, sign(SECRET, &payload).unwrap());
+        assert_eq!(verify(SECRET, &token), None);
+    }
+
+    #[test]
+    fn tampered() {
+        let token = issue(SECRET, "alice", 60).unwrap();
+        //  Another user or a later expiration with the original signature
+        assert_eq!(verify(SECRET, &token.replacen("alice", "mallory", 1)), None);
+        let (payload, signature) = token.rsplit_once('.').unwrap();
+        let (user, expires) = payload.rsplit_once('.').unwrap();
+        let later = expires.parse::<u64>().unwrap() + 3600;
+        assert_eq!(verify(SECRET, &format!("{}.{}.{}", user, later, signature)), None);
+        //  A changed or truncated signature
+        let flipped = if signature.starts_with('0') { "1" } else { "0" };
+        assert_eq!(verify(SECRET, &format!("{}.{}{}", payload, flipped, &signature[1..])), None);
+        assert_eq!(verify(SECRET, &token[..token.len() - 2]), None);
+        assert_eq!(verify(SECRET, "alice"), None);
+        assert_eq!(verify(SECRET, ""), None);
+    }
+
+    #[test]
+    fn wrong_secret() {
+        let token = issue(SECRET, "alice", 60).unwrap();
+        assert_eq!(verify("another-secret-of-another-site", &token), None);
+    }
+}
@@ -0,0 +1,205 @@
+//  Upstream connections
+//  Every microservice can have its own TLS settings: a CA bundle to trust, a client certificate, a server name to verify and certificate pins
+//  For each of them we start a separate ClientConnector with its own SslConnector, the others use the default connector of actix-web
+use actix::prelude::*;
+use actix::actors::resolver::{Connect, Resolver, ResolverError};
+use actix_web::client::ClientConnector;
+use failure::{format_err, Error};
+use futures::Future;
+use log::{error, info};
+use openssl::hash::MessageDigest;
+use openssl::ssl::{SslConnector, SslFiletype, SslMethod, SslVerifyMode};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use tokio_tcp::TcpStream;
+use crate::config::{ConfigUpdate, Upstreams, UpstreamTls};
+
+//  Pins are written in hex, with or without colons between the bytes. A pin that isn't a SHA-256 fingerprint would never
+//  match, so it's an error instead of a connector that refuses every server
+fn pins(pins: &[String]) -> Result<Vec<String>, Error> {
+    pins.iter()
+        .map(|pin| {
+            let fingerprint = pin.replace(':', "").to_lowercase();
+            if fingerprint.len() == 64 && fingerprint.chars().all(|c| c.is_ascii_hexdigit()) {
+                Ok(fingerprint)
+            } else {
+                Err(format_err!("Invalid certificate pin {}", pin))
+            }
+        })
+        .collect()
+}
+
+//  Builds an SslConnector from the TLS section of an upstream
+pub fn connector(tls: &UpstreamTls) -> Result<SslConnector, Error> {
+    let mut builder = SslConnector::builder(SslMethod::tls())?;
+    if let Some(ca) = &tls.ca {
+        builder.set_ca_file(ca)?;
+    }
+    if let (Some(certificate), Some(key)) = (&tls.certificate, &tls.private_key) {
+        builder.set_certificate_chain_file(certificate)?;
+        builder.set_private_key_file(key, SslFiletype::PEM)?;
+        builder.check_private_key()?;
+    }
+    if !tls.pins.is_empty() {
+        //  Pins are SHA-256 fingerprints of the server certificate, the chain still has to be valid
+        let pins = pins(&tls.pins)?;
+        builder.set_verify_callback(SslVerifyMode::PEER, move |valid, store| {
+            if !valid || store.error_depth() != 0 {
+                return valid;
+            }
+            let fingerprint = store.current_cert()
+                .and_then(|cert| cert.digest(MessageDigest::sha256()).ok())
+                .map(|digest| digest.iter().map(|b| format!("{:02x}", b)).collect::<String>());
+            match fingerprint {
+                Some(fingerprint) => pins.contains(&fingerprint),
+                None => false,
+            }
+        });
+    }
+    Ok(builder.build())
+}
+
+//  Resolver that sends connections to the host of the configured url, it's used when the url host is replaced with a server name
+//  so the server name is used for SNI and certificate verification while the connection goes to the real address
+pub struct HostResolver {
+    host: String,
+}
+
+impl Actor for HostResolver {
+    type Context = Context<Self>;
+}
+
+impl Handler<Connect> for HostResolver {
+    type Result = ResponseFuture<TcpStream, ResolverError>;
+
+    fn handle(&mut self, msg: Connect, _: &mut Self::Context) -> Self::Result {
+        let msg = Connect {
+            name: self.host.clone(),
+            port: msg.port,
+            timeout: msg.timeout,
+        };
+        let fut = Resolver::from_registry().send(msg)
+            .then(|res| match res {
+                Ok(res) => res,
+                Err(err) => Err(ResolverError::Resolver(err.to_string())),
+            });
+        Box::new(fut)
+    }
+}
+
+//  Starts the connectors for the upstreams that have TLS settings
+fn start(upstreams: &Upstreams) -> Result<HashMap<&'static str, Addr<ClientConnector>>, Error> {
+    let mut connectors = HashMap::new();
+    for (name, upstream) in upstreams.iter() {
+        if let Some(tls) = &upstream.tls {
+            let mut client = ClientConnector::with_connector(connector(tls)?);
+            if tls.server_name.is_some() {
+                let host = upstream.host()?;
+                client = client.resolver(HostResolver { host }.start());
+            }
+            connectors.insert(name, client.start());
+        }
+    }
+    Ok(connectors)
+}
+
+//  The connectors are shared between workers, handlers take the connector of an upstream for every request
+#[derive(Clone)]
+pub struct UpstreamClients(Arc<RwLock<HashMap<&'static str, Addr<ClientConnector>>>>);
+
+impl UpstreamClients {
+    pub fn new(upstreams: &Upstreams) -> Result<Self, Error> {
+        Ok(UpstreamClients(Arc::new(RwLock::new(start(upstreams)?))))
+    }
+
+    pub fn get(&self, name: &str) -> Addr<ClientConnector> {
+        self.0.read().unwrap().get(name)
+            .cloned()
+            .unwrap_or_else(ClientConnector::from_registry)
+    }
+}
+
+//  Upstream Actor
+//  Restarts the connectors when the TLS settings of upstreams change
+pub struct UpstreamActor {
+    upstreams: Upstreams,
+    clients: UpstreamClients,
+}
+
+impl UpstreamActor {
+    pub fn new(upstreams: Upstreams, clients: UpstreamClients) -> Self {
+        Self {
+            upstreams,
+            clients,
+        }
+    }
+}
+
+impl Actor for UpstreamActor {
+    type Context = Context<Self>;
+}
+
+impl Handler<ConfigUpdate> for UpstreamActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: ConfigUpdate, _: &mut Self::Context) -> Self::Result {
+        let ConfigUpdate(config) = msg;
+        let changed = self.upstreams.iter().iter().zip(config.upstreams.iter().iter())
+            .any(|((_, old), (_, new))| old.tls != new.tls || (new.tls.is_some() && old.url != new.url));
+        if !changed {
+            return;
+        }
+        match start(&config.upstreams) {
+            Ok(connectors) => {
+                *self.clients.0.write().unwrap() = connectors;
+                self.upstreams = config.upstreams.clone();
+                info!("Upstream connectors restarted");
+            }
+            Err(err) => {
+                error!("Upstream TLS settings rejected, keeping the old connectors: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PIN: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    fn tls(pins: &[&str]) -> UpstreamTls {
+        UpstreamTls {
+            ca: None,
+            certificate: None,
+            private_key: None,
+            server_name: None,
+            pins: pins.iter().map(|pin| pin.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn pins_are_normalised() {
+        let colons = PIN.to_uppercase().as_bytes().chunks(2)
+            .map(|byte| std::str::from_utf8(byte).unwrap())
+            .collect::<Vec<_>>()
+            .join(":");
+        assert_eq!(pins(&[PIN.to_owned(), colons]).unwrap(), vec![PIN, PIN]);
+    }
+
+    #[test]
+    fn invalid_pins_are_refused() {
+        for pin in &["", "9f86d081", &PIN[1..], &format!("{}00", PIN), &PIN.replace('9', "g")] {
+            assert!(pins(&[pin.to_string()]).is_err(), "{:?} was accepted", pin);
+        }
+    }
+
+    #[test]
+    fn connectors_are_built_from_the_settings() {
+        assert!(connector(&tls(&[])).is_ok());
+        assert!(connector(&tls(&[PIN])).is_ok());
+        assert!(connector(&tls(&["sha256"])).is_err());
+        let missing = UpstreamTls { ca: Some("/nonexistent/ca.pem".to_owned()), ..tls(&[]) };
+        assert!(connector(&missing).is_err());
+    }
+}